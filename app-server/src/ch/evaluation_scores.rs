@@ -5,7 +5,7 @@ use uuid::Uuid;
 
 use crate::evaluations::utils::EvaluationDatapointResult;
 
-use super::utils::{execute_query, validate_string_against_injection};
+use super::utils::{execute_query, validate_string_against_injection, QueryOptions};
 
 /// Evaluation score
 #[derive(Row, Serialize)]
@@ -104,7 +104,8 @@ pub async fn get_average_evaluation_score(
         name
     );
 
-    let rows: Vec<AverageEvaluationScore> = execute_query(&clickhouse, &query).await?;
+    let rows: Vec<AverageEvaluationScore> =
+        execute_query(&clickhouse, &QueryOptions::default(), &query).await?;
     Ok(rows[0].average_value)
 }
 
@@ -162,7 +163,8 @@ ORDER BY intervals.interval_num",
         interval_nums, lower_bound, step_size, bucket_count, upper_bound, lower_bound, step_size, bucket_count, project_id, evaluation_id, name
     );
 
-    let rows: Vec<EvaluationScoreBucket> = execute_query(&clickhouse, &query).await?;
+    let rows: Vec<EvaluationScoreBucket> =
+        execute_query(&clickhouse, &QueryOptions::default(), &query).await?;
 
     Ok(rows)
 }
@@ -197,6 +199,7 @@ WHERE project_id = '{}'
         project_id, evaluation_ids_str, name
     );
 
-    let rows: Vec<ComparedEvaluationScoresBounds> = execute_query(&clickhouse, &query).await?;
+    let rows: Vec<ComparedEvaluationScoresBounds> =
+        execute_query(&clickhouse, &QueryOptions::default(), &query).await?;
     Ok(rows[0].clone())
 }
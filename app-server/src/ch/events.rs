@@ -8,11 +8,8 @@ use uuid::Uuid;
 use crate::db::{self, event_templates::EventTemplate};
 
 use super::{
-    modifiers::GroupByInterval,
-    utils::{
-        chrono_to_nanoseconds, execute_query, group_by_time_absolute_statement,
-        group_by_time_relative_statement,
-    },
+    modifiers::{GroupByInterval, TimeRange},
+    utils::{chrono_to_nanoseconds, execute_query, QueryOptions},
     MetricTimeValue,
 };
 
@@ -116,12 +113,12 @@ pub async fn insert_events(clickhouse: clickhouse::Client, events: Vec<CHEvent>)
     }
 }
 
-pub async fn get_total_event_count_metrics_relative(
+pub async fn get_total_event_count_metrics(
     clickhouse: clickhouse::Client,
     group_by_interval: GroupByInterval,
     project_id: Uuid,
     template_id: Uuid,
-    past_hours: i64,
+    time_range: TimeRange,
 ) -> Result<Vec<MetricTimeValue<i64>>> {
     let ch_round_time = group_by_interval.to_ch_truncate_time();
 
@@ -134,14 +131,34 @@ pub async fn get_total_event_count_metrics_relative(
     WHERE
         project_id = '{project_id}'
         AND template_id = '{template_id}'
-        AND timestamp >= now() - INTERVAL {past_hours} HOUR
+        AND {}
     {}",
-        group_by_time_relative_statement(past_hours, group_by_interval),
+        time_range.to_ch_condition("timestamp"),
+        time_range.to_ch_group_by_time(group_by_interval),
     );
 
-    execute_query(&clickhouse, &query_string).await
+    execute_query(&clickhouse, &QueryOptions::default(), &query_string).await
 }
 
+#[deprecated(note = "use `get_total_event_count_metrics` with a `TimeRange` instead")]
+pub async fn get_total_event_count_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    template_id: Uuid,
+    past_hours: i64,
+) -> Result<Vec<MetricTimeValue<i64>>> {
+    get_total_event_count_metrics(
+        clickhouse,
+        group_by_interval,
+        project_id,
+        template_id,
+        TimeRange::Relative { past_hours },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_total_event_count_metrics` with a `TimeRange` instead")]
 pub async fn get_total_event_count_metrics_absolute(
     clickhouse: clickhouse::Client,
     group_by_interval: GroupByInterval,
@@ -150,24 +167,15 @@ pub async fn get_total_event_count_metrics_absolute(
     start_time: DateTime<Utc>,
     end_time: DateTime<Utc>,
 ) -> Result<Vec<MetricTimeValue<i64>>> {
-    let ch_round_time = group_by_interval.to_ch_truncate_time();
-    let ch_start_time = start_time.timestamp();
-    let ch_end_time = end_time.timestamp();
-
-    let query_string = format!(
-        "
-    SELECT
-        {ch_round_time}(timestamp) AS time,
-        COUNT(DISTINCT id) AS value
-    FROM events
-    WHERE
-        project_id = '{project_id}'
-        AND template_id = '{template_id}'
-        AND timestamp >= fromUnixTimestamp({ch_start_time})
-        AND timestamp <= fromUnixTimestamp({ch_end_time})
-    {}",
-        group_by_time_absolute_statement(start_time, end_time, group_by_interval)
-    );
-
-    execute_query(&clickhouse, &query_string).await
+    get_total_event_count_metrics(
+        clickhouse,
+        group_by_interval,
+        project_id,
+        template_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+    )
+    .await
 }
@@ -0,0 +1,334 @@
+use anyhow::Result;
+use clickhouse::Row;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    modifiers::{to_ch_rounded_cost, FxRate, GroupByInterval, TimeRange, DEFAULT_COST_PRECISION},
+    spans::{span_metric_query, NumericColumn, SpanTable},
+    utils::QueryOptions,
+    Aggregation, FloatMetricTimeValue,
+};
+
+/// Longest trace id allowlist `MetricQuery::trace_ids` accepts, to keep queries small
+pub const MAX_TRACE_ID_FILTER_LEN: usize = 10_000;
+
+/// Restricts the spans that contribute to a metric
+#[derive(Clone)]
+pub enum SpanFilter {
+    Name(String),
+    Model(String),
+    Provider(String),
+    SessionId(String),
+    UserId(String),
+}
+
+impl SpanFilter {
+    fn to_ch_condition(&self) -> &'static str {
+        match self {
+            SpanFilter::Name(_) => "name = ?",
+            SpanFilter::Model(_) => "model = ?",
+            SpanFilter::Provider(_) => "provider = ?",
+            SpanFilter::SessionId(_) => "session_id = ?",
+            SpanFilter::UserId(_) => "user_id = ?",
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            SpanFilter::Name(value)
+            | SpanFilter::Model(value)
+            | SpanFilter::Provider(value)
+            | SpanFilter::SessionId(value)
+            | SpanFilter::UserId(value) => value,
+        }
+    }
+}
+
+/// Builder for per-trace numeric metrics, so that optional features don't keep growing
+/// the parameter lists of the `get_*_metrics` functions.
+///
+/// ```ignore
+/// let values = MetricQuery::new(project_id, NumericColumn::TotalCost)
+///     .time_range(TimeRange::Relative { past_hours: 24 })
+///     .interval(GroupByInterval::Hour)
+///     .filter(SpanFilter::Model("gpt-4o".to_string()))
+///     .execute(clickhouse)
+///     .await?;
+/// ```
+pub struct MetricQuery {
+    project_id: Uuid,
+    column: NumericColumn,
+    table: SpanTable,
+    options: QueryOptions,
+    time_range: TimeRange,
+    group_by_interval: GroupByInterval,
+    aggregation: Aggregation,
+    filters: Vec<SpanFilter>,
+    trace_ids: Option<Vec<Uuid>>,
+    fx_rate: Option<FxRate>,
+    cost_precision: u32,
+    cumulative: bool,
+    sample: Option<f64>,
+}
+
+impl MetricQuery {
+    /// Defaults to the total over the past 24 hours, grouped by hour
+    pub fn new(project_id: Uuid, column: NumericColumn) -> Self {
+        Self {
+            project_id,
+            column,
+            table: SpanTable::default(),
+            options: QueryOptions::default(),
+            time_range: TimeRange::Relative { past_hours: 24 },
+            group_by_interval: GroupByInterval::default(),
+            aggregation: Aggregation::Total,
+            filters: Vec::new(),
+            trace_ids: None,
+            fx_rate: None,
+            cost_precision: DEFAULT_COST_PRECISION,
+            cumulative: false,
+            sample: None,
+        }
+    }
+
+    pub fn table(mut self, table: SpanTable) -> Self {
+        self.table = table;
+        self
+    }
+
+    pub fn options(mut self, options: QueryOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn time_range(mut self, time_range: TimeRange) -> Self {
+        self.time_range = time_range;
+        self
+    }
+
+    pub fn interval(mut self, group_by_interval: GroupByInterval) -> Self {
+        self.group_by_interval = group_by_interval;
+        self
+    }
+
+    pub fn aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    /// Only spans matching all filters contribute to the value of their trace, and the
+    /// trace is bucketed by the earliest matching span
+    pub fn filter(mut self, filter: SpanFilter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Only traces in `trace_ids` contribute, e.g. the results of a search. Fails if there
+    /// are more than `MAX_TRACE_ID_FILTER_LEN` ids.
+    pub fn trace_ids(mut self, trace_ids: Option<&[Uuid]>) -> Result<Self> {
+        if let Some(trace_ids) = trace_ids {
+            if trace_ids.len() > MAX_TRACE_ID_FILTER_LEN {
+                return Err(anyhow::anyhow!(
+                    "At most {} trace ids can be filtered on, got {}",
+                    MAX_TRACE_ID_FILTER_LEN,
+                    trace_ids.len()
+                ));
+            }
+        }
+        self.trace_ids = trace_ids.map(|trace_ids| trace_ids.to_vec());
+        Ok(self)
+    }
+
+    /// Reports `NumericColumn::TotalCost` in another currency. Other columns are not
+    /// affected.
+    pub fn fx_rate(mut self, fx_rate: Option<FxRate>) -> Self {
+        self.fx_rate = fx_rate;
+        self
+    }
+
+    /// Decimal places `NumericColumn::TotalCost` values are rounded to
+    pub fn cost_precision(mut self, decimals: u32) -> Self {
+        self.cost_precision = decimals;
+        self
+    }
+
+    /// Returns the running total since the start of the time range instead of per-bucket
+    /// values, e.g. for budget burndown charts. Only meaningful with `Aggregation::Total`.
+    pub fn cumulative(mut self, cumulative: bool) -> Self {
+        self.cumulative = cumulative;
+        self
+    }
+
+    /// Reads only the `sample` fraction of the table, in (0, 1], for fast approximate
+    /// charts. `Aggregation::Total` values are scaled up by the inverse of the fraction,
+    /// averages and medians are estimated from the sampled traces as they are.
+    ///
+    /// The table must have a sampling key, e.g. `SAMPLE BY cityHash64(trace_id)`, which
+    /// keeps the spans of a trace together and must be part of its primary key. `execute`
+    /// fails on tables without one.
+    pub fn sample(mut self, sample: Option<f64>) -> Result<Self> {
+        if let Some(sample) = sample {
+            if !(sample > 0.0 && sample <= 1.0) {
+                return Err(anyhow::anyhow!("Sample must be in (0, 1], got {}", sample));
+            }
+        }
+        self.sample = sample;
+        Ok(self)
+    }
+
+    /// Final SQL of the metric. Filter values, trace ids and the project id are left as `?`
+    /// placeholders, which `execute` binds in order. The sample fraction is validated by
+    /// `sample`, so it is inlined, as it appears both before and after them in the SQL.
+    pub fn build_query(&self) -> String {
+        let expression = self.column.to_ch_trace_expression();
+        let expression = match self.column {
+            NumericColumn::TotalCost => FxRate::to_ch_cost(self.fx_rate, expression),
+            _ => expression.to_string(),
+        };
+        let metric = format!("toFloat64({expression})");
+        let mut span_conditions = self
+            .filters
+            .iter()
+            .map(|filter| filter.to_ch_condition().to_string())
+            .collect::<Vec<String>>();
+        match self.trace_ids.as_deref() {
+            // An empty array has no element type to compare trace ids with
+            Some([]) => span_conditions.push("0".to_string()),
+            Some(_) => span_conditions.push("trace_id IN ?".to_string()),
+            None => {}
+        }
+
+        let from_table = match self.sample {
+            Some(sample) => format!("{} SAMPLE {sample:?}", self.table),
+            None => self.table.to_string(),
+        };
+
+        let query_string = span_metric_query(
+            &from_table,
+            self.group_by_interval,
+            &self.time_range,
+            self.aggregation,
+            &metric,
+            &span_conditions,
+            false,
+        );
+
+        let query_string = match (self.sample, self.aggregation) {
+            (Some(sample), Aggregation::Total) => format!(
+                "
+    SELECT
+        time,
+        value / {sample:?} AS value
+    FROM ({query_string})
+    ORDER BY time"
+            ),
+            _ => query_string,
+        };
+
+        let query_string = if self.cumulative {
+            // The window runs over the already filled buckets, so empty buckets carry
+            // the running total forward
+            format!(
+                "
+    SELECT
+        time,
+        sum(value) OVER (ORDER BY time ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW)
+            AS value
+    FROM ({query_string})
+    ORDER BY time"
+            )
+        } else {
+            query_string
+        };
+
+        match self.column {
+            // Rounded last, so that running totals don't accumulate the noise again
+            NumericColumn::TotalCost => format!(
+                "
+    SELECT
+        time,
+        {} AS value
+    FROM ({query_string})
+    ORDER BY time",
+                to_ch_rounded_cost("value", self.cost_precision)
+            ),
+            _ => query_string,
+        }
+    }
+
+    pub async fn execute(
+        self,
+        clickhouse: clickhouse::Client,
+    ) -> Result<Vec<FloatMetricTimeValue>> {
+        if self.sample.is_some() && !has_sampling_key(&clickhouse, &self.table).await? {
+            return Err(anyhow::anyhow!(
+                "Sampling was requested, but table {} has no sampling key",
+                self.table
+            ));
+        }
+
+        let query_string = self.build_query();
+        let mut query = self.options.apply(&clickhouse).query(&query_string);
+        for filter in &self.filters {
+            query = query.bind(filter.value());
+        }
+        if let Some(trace_ids) = self.trace_ids.as_deref().filter(|ids| !ids.is_empty()) {
+            query = query.bind(trace_ids);
+        }
+        query = query.bind(std::slice::from_ref(&self.project_id));
+
+        self.options
+            .run(&clickhouse, async {
+                Ok(query.fetch_all::<FloatMetricTimeValue>().await?)
+            })
+            .await
+    }
+}
+
+#[derive(Row, Deserialize)]
+struct SamplingKey {
+    sampling_key: String,
+}
+
+async fn has_sampling_key(clickhouse: &clickhouse::Client, table: &SpanTable) -> Result<bool> {
+    let row = clickhouse
+        .query(
+            "SELECT sampling_key FROM system.tables
+            WHERE database = currentDatabase() AND name = ?",
+        )
+        .bind(table.as_str())
+        .fetch_optional::<SamplingKey>()
+        .await?;
+
+    Ok(row.is_some_and(|row| !row.sampling_key.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ch::utils::PROJECT_IDS_CONDITION;
+
+    #[test]
+    fn test_sampled_query_placeholders_match_binds() {
+        let trace_ids = [Uuid::new_v4()];
+        let query = MetricQuery::new(Uuid::nil(), NumericColumn::TotalTokens)
+            .filter(SpanFilter::Model(String::from("gpt-4o")))
+            .filter(SpanFilter::UserId(String::from("user")))
+            .trace_ids(Some(&trace_ids))
+            .unwrap()
+            .sample(Some(0.1))
+            .unwrap();
+        let query_string = query.build_query();
+
+        assert!(query_string.contains("SAMPLE 0.1"));
+        assert!(query_string.contains("value / 0.1 AS value"));
+        // `execute` binds the filters in order, then the trace ids and the project id
+        assert_eq!(query_string.matches('?').count(), 4);
+        let model = query_string.find("model = ?").unwrap();
+        let user_id = query_string.find("user_id = ?").unwrap();
+        let trace_id = query_string.find("trace_id IN ?").unwrap();
+        let project_id = query_string.find(PROJECT_IDS_CONDITION).unwrap();
+        assert!(model < user_id && user_id < trace_id && trace_id < project_id);
+    }
+}
@@ -1,16 +1,25 @@
+use std::collections::HashMap;
+
 use clickhouse::Row;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 pub mod evaluation_scores;
 pub mod events;
+pub mod metric_query;
+pub mod model_daily_summary;
 pub mod modifiers;
+pub mod prometheus;
+pub mod query_templates;
 pub mod spans;
+pub mod trace_aggregates;
 pub mod utils;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Aggregation {
     Total,
     Average,
+    Median,
 }
 
 impl Aggregation {
@@ -18,6 +27,29 @@ impl Aggregation {
         match self {
             Aggregation::Total => "SUM",
             Aggregation::Average => "AVG",
+            Aggregation::Median => "median",
+        }
+    }
+}
+
+/// How distinct values are counted.
+///
+/// `Exact` keeps every distinct value in memory, which gets slow and memory hungry for
+/// projects with millions of traces per range. `Approximate` uses ClickHouse's `uniq`,
+/// an adaptive sampling estimate that stays within about 2% and uses a fixed amount of
+/// memory per bucket.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CountMode {
+    #[default]
+    Exact,
+    Approximate,
+}
+
+impl CountMode {
+    pub fn to_ch_count_distinct(&self, expression: &str) -> String {
+        match self {
+            CountMode::Exact => format!("uniqExact({expression})"),
+            CountMode::Approximate => format!("uniq({expression})"),
         }
     }
 }
@@ -27,3 +59,165 @@ pub struct MetricTimeValue<T> {
     pub time: u32,
     pub value: T,
 }
+
+/// Several aggregations of a metric within a time bucket, in the order they were
+/// requested
+#[derive(Deserialize, Row, Serialize)]
+pub struct MultiMetricTimeValue {
+    pub time: u32,
+    pub values: Vec<f64>,
+}
+
+/// Metric value of one named series within a time bucket
+#[derive(Deserialize, Row, Serialize)]
+pub struct NameMetricTimeValue {
+    pub time: u32,
+    pub name: String,
+    pub value: f64,
+}
+
+/// Metric value of one session within a time bucket
+#[derive(Deserialize, Row, Serialize)]
+pub struct SessionMetricTimeValue {
+    pub time: u32,
+    pub session_id: String,
+    pub value: f64,
+}
+
+/// Usage of one model within a time bucket. The input and output costs are only
+/// present if they were requested.
+#[derive(Deserialize, Row, Serialize)]
+pub struct ModelMetricTimeValue {
+    pub time: u32,
+    pub model: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    pub total_cost: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_cost: Option<f64>,
+}
+
+/// Metric value of one model within a time bucket
+#[derive(Deserialize, Row, Serialize)]
+pub struct ModelFloatMetricTimeValue {
+    pub time: u32,
+    pub model: String,
+    pub value: f64,
+}
+
+/// Metric value of one provider within a time bucket
+#[derive(Deserialize, Row, Serialize)]
+pub struct ProviderMetricTimeValue {
+    pub time: u32,
+    pub provider: String,
+    pub value: f64,
+}
+
+/// Metric value of one project within a time bucket
+#[derive(Deserialize, Row, Serialize)]
+pub struct ProjectMetricTimeValue {
+    pub time: u32,
+    #[serde(with = "clickhouse::serde::uuid")]
+    pub project_id: Uuid,
+    pub value: f64,
+}
+
+/// Value of one series of a breakdown within a time bucket
+pub trait SeriesMetricTimeValue {
+    fn time(&self) -> u32;
+    fn value_mut(&mut self) -> &mut f64;
+}
+
+macro_rules! impl_series_metric_time_value {
+    ($($t:ty),*) => {
+        $(impl SeriesMetricTimeValue for $t {
+            fn time(&self) -> u32 {
+                self.time
+            }
+
+            fn value_mut(&mut self) -> &mut f64 {
+                &mut self.value
+            }
+        })*
+    };
+}
+
+impl_series_metric_time_value!(
+    NameMetricTimeValue,
+    SessionMetricTimeValue,
+    ProviderMetricTimeValue,
+    ProjectMetricTimeValue
+);
+
+/// Converts a breakdown into each series' percentage of its bucket's total. Buckets
+/// with a total of 0 get 0 for every series.
+pub fn to_percent_of_total<T: SeriesMetricTimeValue>(values: &mut [T]) {
+    let mut totals = HashMap::<u32, f64>::new();
+    for value in values.iter_mut() {
+        *totals.entry(value.time()).or_default() += *value.value_mut();
+    }
+
+    for value in values.iter_mut() {
+        let total = totals[&value.time()];
+        let value_mut = value.value_mut();
+        *value_mut = if total == 0.0 {
+            0.0
+        } else {
+            *value_mut / total * 100.0
+        };
+    }
+}
+
+pub type IntMetricTimeValue = MetricTimeValue<i64>;
+pub type FloatMetricTimeValue = MetricTimeValue<f64>;
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricUnit {
+    Count,
+    Usd,
+    Seconds,
+    Tokens,
+}
+
+#[derive(Serialize)]
+pub struct MetricPoint<T> {
+    /// Start of the bucket, in seconds since the Unix epoch
+    pub timestamp_sec: u32,
+    pub value: T,
+}
+
+/// Self-describing form of a metric for API responses, serialized as
+/// `{ "unit": "usd", "points": [{ "timestamp_sec": ..., "value": ... }] }`
+#[derive(Serialize)]
+pub struct MetricSeries<T> {
+    pub unit: MetricUnit,
+    /// Currency of `usd` unit values converted with an exchange rate, e.g. "EUR"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    pub points: Vec<MetricPoint<T>>,
+}
+
+impl<T> MetricSeries<T> {
+    pub fn new(unit: MetricUnit, values: Vec<MetricTimeValue<T>>) -> Self {
+        Self {
+            unit,
+            currency: None,
+            points: values
+                .into_iter()
+                .map(|v| MetricPoint {
+                    timestamp_sec: v.time,
+                    value: v.value,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn with_currency(mut self, currency: &str) -> Self {
+        self.currency = Some(currency.to_string());
+        self
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    modifiers::to_ch_rounded_cost,
+    spans::{SpanTable, NULL_SENTINEL},
+    utils::{
+        aggregate_table_backfilled, create_aggregate_table, refresh_aggregate_table, QueryOptions,
+    },
+};
+
+/// Per-project, per-day cost and tokens of every model, maintained by a materialized
+/// view over the spans table so that model leaderboards don't scan the spans
+pub const MODEL_DAILY_SUMMARY_TABLE: &str = "model_daily_summary";
+const MODEL_DAILY_SUMMARY_VIEW: &str = "model_daily_summary_mv";
+
+/// Statements creating the `model_daily_summary` table and the materialized view that
+/// populates it on every insert into `span_table`. Spans without a model are left out.
+///
+/// Rows of the same model and day are only combined when ClickHouse merges parts, so
+/// readers must still aggregate by model.
+pub fn model_daily_summary_ddl(span_table: &SpanTable) -> Vec<String> {
+    vec![
+        format!(
+            "CREATE TABLE IF NOT EXISTS {MODEL_DAILY_SUMMARY_TABLE}
+            (
+                project_id UUID,
+                day Date,
+                model String,
+                span_count SimpleAggregateFunction(sum, UInt64),
+                sum_tokens SimpleAggregateFunction(sum, Int64),
+                sum_cost SimpleAggregateFunction(sum, Float64)
+            )
+            ENGINE = AggregatingMergeTree()
+            ORDER BY (project_id, day, model)"
+        ),
+        format!(
+            "CREATE MATERIALIZED VIEW IF NOT EXISTS {MODEL_DAILY_SUMMARY_VIEW}
+            TO {MODEL_DAILY_SUMMARY_TABLE}
+            AS {}",
+            model_daily_summary_select(span_table)
+        ),
+    ]
+}
+
+fn model_daily_summary_select(span_table: &impl fmt::Display) -> String {
+    format!(
+        "SELECT
+            project_id,
+            toDate(start_time) AS day,
+            model,
+            count() AS span_count,
+            SUM(total_tokens) AS sum_tokens,
+            SUM(total_cost) AS sum_cost
+        FROM {span_table}
+        WHERE model != '{NULL_SENTINEL}'
+        GROUP BY project_id, day, model"
+    )
+}
+
+pub async fn create_model_daily_summary(
+    clickhouse: clickhouse::Client,
+    span_table: &SpanTable,
+) -> Result<()> {
+    create_aggregate_table(
+        &clickhouse,
+        MODEL_DAILY_SUMMARY_TABLE,
+        &model_daily_summary_ddl(span_table),
+    )
+    .await
+}
+
+/// Recomputes the daily model totals from the spans table, e.g. after creating the view
+/// on a populated table, or after `upsert_span` corrections, which the view counts as
+/// additional spans.
+///
+/// Needs ingestion to be paused, see `refresh_aggregate_table`.
+pub async fn refresh_model_daily_summary(
+    clickhouse: clickhouse::Client,
+    span_table: &SpanTable,
+) -> Result<()> {
+    refresh_aggregate_table(
+        &clickhouse,
+        MODEL_DAILY_SUMMARY_TABLE,
+        span_table,
+        |source| model_daily_summary_select(&source),
+    )
+    .await
+}
+
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelUsage {
+    pub model: String,
+    pub span_count: u64,
+    pub total_tokens: i64,
+    pub total_cost: f64,
+}
+
+/// The `limit` models with the highest cost over the days from `start_time` to
+/// `end_time`, both included, most expensive first. Days are UTC, so the bounds are only
+/// exact to a day. Until `model_daily_summary` has been refreshed once, the totals are
+/// computed from `span_table` instead, which is slower but complete.
+pub async fn get_top_models_by_cost(
+    clickhouse: clickhouse::Client,
+    span_table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<ModelUsage>> {
+    let source =
+        if aggregate_table_backfilled(&clickhouse, options, MODEL_DAILY_SUMMARY_TABLE).await? {
+            MODEL_DAILY_SUMMARY_TABLE.to_string()
+        } else {
+            format!("({})", model_daily_summary_select(span_table))
+        };
+    let total_cost = to_ch_rounded_cost("toFloat64(SUM(sum_cost))", options.cost_precision());
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                model,
+                toUInt64(SUM(span_count)) AS span_count,
+                toInt64(SUM(sum_tokens)) AS total_tokens,
+                {total_cost} AS total_cost
+            FROM {source}
+            WHERE project_id = ?
+                AND day >= toDate(fromUnixTimestamp(?))
+                AND day <= toDate(fromUnixTimestamp(?))
+            GROUP BY model
+            ORDER BY total_cost DESC, model ASC
+            LIMIT ?"
+        ))
+        .bind(project_id)
+        .bind(start_time.timestamp())
+        .bind(end_time.timestamp())
+        .bind(limit);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<ModelUsage>().await?)
+        })
+        .await
+}
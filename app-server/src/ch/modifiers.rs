@@ -1,6 +1,13 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
 use serde::Deserialize;
 
-#[derive(Deserialize, Clone, Copy)]
+use super::utils::{
+    group_by_time_absolute_statement, group_by_time_relative_statement,
+    with_fill_absolute_statement, with_fill_relative_statement,
+};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(rename_all = "camelCase")]
 pub enum GroupByInterval {
     Minute,
@@ -15,6 +22,15 @@ impl Default for GroupByInterval {
 }
 
 impl GroupByInterval {
+    /// Name of the interval, as it is deserialized from requests
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupByInterval::Minute => "minute",
+            GroupByInterval::Hour => "hour",
+            GroupByInterval::Day => "day",
+        }
+    }
+
     pub fn to_ch_truncate_time(&self) -> &str {
         match self {
             GroupByInterval::Minute => "toStartOfMinute",
@@ -38,4 +54,231 @@ impl GroupByInterval {
             GroupByInterval::Day => "toIntervalDay(1)",
         }
     }
+
+    pub fn to_seconds(&self) -> i64 {
+        match self {
+            GroupByInterval::Minute => 60,
+            GroupByInterval::Hour => 60 * 60,
+            GroupByInterval::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// Next coarser interval, or `None` if this is already the coarsest one
+    pub fn coarser(&self) -> Option<GroupByInterval> {
+        match self {
+            GroupByInterval::Minute => Some(GroupByInterval::Hour),
+            GroupByInterval::Hour => Some(GroupByInterval::Day),
+            GroupByInterval::Day => None,
+        }
+    }
+}
+
+/// Time range of a metric query, either the last `past_hours` or a fixed window
+#[derive(Clone, Copy)]
+pub enum TimeRange {
+    Relative {
+        past_hours: i64,
+    },
+    Absolute {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+}
+
+impl TimeRange {
+    /// Opt-in snapping of a relative range to bucket boundaries. The range becomes
+    /// absolute, starting at the beginning of the `group_by_interval` bucket that
+    /// contains `now - past_hours`, so the first bucket covers a whole interval instead
+    /// of a partial one. Buckets are in UTC, like `toStartOf*` on the span columns.
+    /// Absolute ranges are returned unchanged.
+    pub fn aligned_to_calendar(self, group_by_interval: GroupByInterval) -> Self {
+        self.aligned_to_calendar_at(Utc::now(), group_by_interval)
+    }
+
+    fn aligned_to_calendar_at(
+        self,
+        now: DateTime<Utc>,
+        group_by_interval: GroupByInterval,
+    ) -> Self {
+        match self {
+            TimeRange::Relative { past_hours } => {
+                let start = (now - Duration::hours(past_hours)).timestamp();
+                let start = start - start.rem_euclid(group_by_interval.to_seconds());
+                TimeRange::Absolute {
+                    start: DateTime::from_timestamp(start, 0).unwrap_or(now),
+                    end: now,
+                }
+            }
+            absolute => absolute,
+        }
+    }
+
+    pub fn duration_seconds(&self) -> i64 {
+        match self {
+            TimeRange::Relative { past_hours } => past_hours * 60 * 60,
+            TimeRange::Absolute { start, end } => (*end - *start).num_seconds(),
+        }
+    }
+
+    /// SQL expressions for the start and end of the time range
+    pub fn to_ch_bounds(&self) -> (String, String) {
+        match self {
+            TimeRange::Relative { past_hours } => (
+                format!("now() - INTERVAL {past_hours} HOUR"),
+                "now()".to_string(),
+            ),
+            TimeRange::Absolute { start, end } => (
+                format!("fromUnixTimestamp({})", start.timestamp()),
+                format!("fromUnixTimestamp({})", end.timestamp()),
+            ),
+        }
+    }
+
+    /// Condition restricting `column` to the time range
+    pub fn to_ch_condition(&self, column: &str) -> String {
+        match self {
+            TimeRange::Relative { past_hours } => {
+                format!("{column} >= now() - INTERVAL {past_hours} HOUR")
+            }
+            TimeRange::Absolute { start, end } => format!(
+                "{column} >= fromUnixTimestamp({})
+        AND {column} <= fromUnixTimestamp({})",
+                start.timestamp(),
+                end.timestamp()
+            ),
+        }
+    }
+
+    /// `WITH FILL ... FROM ... TO ...` modifier covering the time range
+    pub fn to_ch_with_fill(&self, group_by_interval: GroupByInterval) -> String {
+        match self {
+            TimeRange::Relative { past_hours } => {
+                with_fill_relative_statement(*past_hours, group_by_interval)
+            }
+            TimeRange::Absolute { start, end } => {
+                with_fill_absolute_statement(*start, *end, group_by_interval)
+            }
+        }
+    }
+
+    /// `GROUP BY time ORDER BY time WITH FILL ...` statement covering the time range
+    pub fn to_ch_group_by_time(&self, group_by_interval: GroupByInterval) -> String {
+        match self {
+            TimeRange::Relative { past_hours } => {
+                group_by_time_relative_statement(*past_hours, group_by_interval)
+            }
+            TimeRange::Absolute { start, end } => {
+                group_by_time_absolute_statement(*start, *end, group_by_interval)
+            }
+        }
+    }
+}
+
+/// Exchange rate from USD, which costs are stored in, to the currency they should be
+/// reported in. Rates are supplied by the caller and never fetched.
+#[derive(Clone, Copy, Debug)]
+pub struct FxRate(f64);
+
+impl FxRate {
+    pub fn new(rate: f64) -> Result<Self> {
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(anyhow::anyhow!("Invalid exchange rate: {}", rate));
+        }
+        Ok(Self(rate))
+    }
+
+    /// Converts a USD cost expression, or returns it unchanged without a rate
+    pub fn to_ch_cost(fx_rate: Option<FxRate>, expression: &str) -> String {
+        match fx_rate {
+            // {:?} keeps the decimal point, so the literal is always a Float64
+            Some(FxRate(rate)) => format!("({expression}) * {rate:?}"),
+            None => expression.to_string(),
+        }
+    }
+}
+
+/// How latency metrics treat spans or traces that end before they start, e.g. because of
+/// clock skew between services. Left as is, their negative latencies would skew
+/// averages and percentiles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NegativeLatencyPolicy {
+    /// Leave them out of the aggregation
+    #[default]
+    Exclude,
+    /// Count them with a latency of 0
+    Clamp,
+}
+
+impl NegativeLatencyPolicy {
+    /// Applies the policy to a latency expression. Excluded values become NULL, which
+    /// aggregate functions skip.
+    pub fn to_ch_latency(&self, expression: &str) -> String {
+        match self {
+            NegativeLatencyPolicy::Exclude => format!("if(({expression}) < 0, NULL, {expression})"),
+            NegativeLatencyPolicy::Clamp => format!("greatest({expression}, 0)"),
+        }
+    }
+}
+
+/// Decimal places cost values are rounded to in query output, unless a caller sets
+/// `QueryOptions::with_cost_precision`
+pub const DEFAULT_COST_PRECISION: u32 = 6;
+
+/// Rounds a cost expression to `precision` decimal places, removing floating point noise
+/// such as `0.30000000000000004` from sums
+pub fn to_ch_rounded_cost(expression: &str, precision: u32) -> String {
+    format!("round({expression}, {precision})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negative_latency_policy() {
+        assert_eq!(
+            NegativeLatencyPolicy::Exclude.to_ch_latency("end - start"),
+            "if((end - start) < 0, NULL, end - start)"
+        );
+        assert_eq!(
+            NegativeLatencyPolicy::Clamp.to_ch_latency("end - start"),
+            "greatest(end - start, 0)"
+        );
+    }
+
+    #[test]
+    fn test_aligned_to_calendar() {
+        let now = DateTime::from_timestamp(1_700_003_723, 123_456_789).unwrap();
+        for interval in [
+            GroupByInterval::Minute,
+            GroupByInterval::Hour,
+            GroupByInterval::Day,
+        ] {
+            let TimeRange::Absolute { start, end } =
+                TimeRange::Relative { past_hours: 24 }.aligned_to_calendar_at(now, interval)
+            else {
+                panic!("aligned range should be absolute");
+            };
+            assert_eq!(start.timestamp() % interval.to_seconds(), 0);
+            assert_eq!(start.timestamp_subsec_nanos(), 0);
+            assert!(start <= now - Duration::hours(24));
+            assert!(now - Duration::hours(24) - start < Duration::seconds(interval.to_seconds()));
+            assert_eq!(end, now);
+
+            // The filled buckets start at the aligned start
+            let fill = TimeRange::Absolute { start, end }.to_ch_with_fill(interval);
+            assert!(fill.contains(&format!("(fromUnixTimestamp({}))", start.timestamp())));
+        }
+
+        let absolute = TimeRange::Absolute {
+            start: now - Duration::hours(1),
+            end: now,
+        };
+        let TimeRange::Absolute { start, .. } =
+            absolute.aligned_to_calendar_at(now, GroupByInterval::Hour)
+        else {
+            panic!("absolute range should stay absolute");
+        };
+        assert_eq!(start, now - Duration::hours(1));
+    }
 }
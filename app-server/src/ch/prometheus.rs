@@ -0,0 +1,141 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use clickhouse::Row;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{modifiers::to_ch_rounded_cost, spans::SpanTable, utils::QueryOptions};
+
+/// Totals over the spans started in the current hour, grouped into their traces. Only
+/// the current hour is scanned, so a trace that started in the previous hour counts with
+/// its spans from this hour.
+#[derive(Row, Deserialize, Debug, Default, PartialEq)]
+pub struct CurrentHourTotals {
+    pub trace_count: u64,
+    pub total_tokens: i64,
+    pub total_cost: f64,
+    /// Share of the traces with at least one errored span, 0 if there are no traces
+    pub error_rate: f64,
+}
+
+pub async fn get_current_hour_totals(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+) -> Result<CurrentHourTotals> {
+    let total_cost = to_ch_rounded_cost("toFloat64(SUM(cost))", options.cost_precision());
+    let query_string = format!(
+        "
+    WITH traces AS (
+        SELECT
+            trace_id,
+            SUM(total_tokens) AS tokens,
+            SUM(total_cost) AS cost,
+            max(status = 'error') AS error
+        FROM {table}
+        WHERE project_id = ? AND start_time >= toStartOfHour(now())
+        GROUP BY trace_id
+    )
+    SELECT
+        count() AS trace_count,
+        toInt64(SUM(tokens)) AS total_tokens,
+        {total_cost} AS total_cost,
+        if(count() = 0, 0, countIf(error = 1) / count()) AS error_rate
+    FROM traces"
+    );
+
+    let query = options
+        .apply(&clickhouse)
+        .query(&query_string)
+        .bind(project_id);
+    let totals = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_optional::<CurrentHourTotals>().await?)
+        })
+        .await?;
+
+    Ok(totals.unwrap_or_default())
+}
+
+/// Current-hour totals of the project in the Prometheus text exposition format, with the
+/// project id as a label, so that they can be scraped without the Laminar UI
+pub async fn render_prometheus_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+) -> Result<String> {
+    let totals = get_current_hour_totals(clickhouse, table, options, project_id).await?;
+    Ok(render_totals(&totals, project_id))
+}
+
+fn render_totals(totals: &CurrentHourTotals, project_id: Uuid) -> String {
+    let metrics = [
+        (
+            "laminar_traces_current_hour",
+            "Traces started in the current hour",
+            totals.trace_count.to_string(),
+        ),
+        (
+            "laminar_tokens_current_hour",
+            "Tokens used by traces started in the current hour",
+            totals.total_tokens.to_string(),
+        ),
+        (
+            "laminar_cost_usd_current_hour",
+            "Cost in USD of traces started in the current hour",
+            totals.total_cost.to_string(),
+        ),
+        (
+            "laminar_trace_error_rate_current_hour",
+            "Share of traces started in the current hour with an errored span",
+            totals.error_rate.to_string(),
+        ),
+    ];
+
+    let mut output = String::new();
+    for (name, help, value) in metrics {
+        // Writing to a String never fails
+        let _ = writeln!(output, "# HELP {name} {help}");
+        let _ = writeln!(output, "# TYPE {name} gauge");
+        let _ = writeln!(output, "{name}{{project_id=\"{project_id}\"}} {value}");
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_totals() {
+        let project_id = Uuid::nil();
+        let totals = CurrentHourTotals {
+            trace_count: 3,
+            total_tokens: 120,
+            total_cost: 0.25,
+            error_rate: 1.0 / 3.0,
+        };
+
+        let output = render_totals(&totals, project_id);
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 12);
+        assert_eq!(
+            lines[0],
+            "# HELP laminar_traces_current_hour Traces started in the current hour"
+        );
+        assert_eq!(lines[1], "# TYPE laminar_traces_current_hour gauge");
+        assert_eq!(
+            lines[2],
+            format!("laminar_traces_current_hour{{project_id=\"{project_id}\"}} 3")
+        );
+        assert_eq!(
+            lines[8],
+            format!("laminar_cost_usd_current_hour{{project_id=\"{project_id}\"}} 0.25")
+        );
+    }
+}
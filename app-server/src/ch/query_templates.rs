@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use clickhouse::query::Query;
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+use super::{
+    modifiers::{GroupByInterval, NegativeLatencyPolicy, TimeRange},
+    spans::{per_trace_cte, NumericColumn, SpanTable},
+    Aggregation, CountMode,
+};
+
+/// Metrics shown on the project dashboard. They run on every page load with only the
+/// project and the time range changing, so their SQL is built once per shape and the
+/// variable parts are bound as parameters.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DashboardMetric {
+    TraceCount,
+    TraceLatencySeconds,
+    TotalTokens,
+    TotalCost,
+}
+
+impl DashboardMetric {
+    pub fn from_numeric_column(column: NumericColumn) -> Option<Self> {
+        match column {
+            NumericColumn::TraceLatencySeconds => Some(DashboardMetric::TraceLatencySeconds),
+            NumericColumn::TotalTokens => Some(DashboardMetric::TotalTokens),
+            NumericColumn::TotalCost => Some(DashboardMetric::TotalCost),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct TemplateKey {
+    metric: DashboardMetric,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    relative: bool,
+}
+
+const AGGREGATIONS: [Aggregation; 3] = [
+    Aggregation::Total,
+    Aggregation::Average,
+    Aggregation::Median,
+];
+const INTERVALS: [GroupByInterval; 3] = [
+    GroupByInterval::Minute,
+    GroupByInterval::Hour,
+    GroupByInterval::Day,
+];
+const NUMERIC_METRICS: [(DashboardMetric, NumericColumn); 3] = [
+    (
+        DashboardMetric::TraceLatencySeconds,
+        NumericColumn::TraceLatencySeconds,
+    ),
+    (DashboardMetric::TotalTokens, NumericColumn::TotalTokens),
+    (DashboardMetric::TotalCost, NumericColumn::TotalCost),
+];
+
+lazy_static! {
+    static ref DASHBOARD_TEMPLATES: HashMap<TemplateKey, String> = build_dashboard_templates();
+}
+
+/// SQL of a dashboard metric over the default spans table, with the project id, the cost
+/// precision of `TotalCost` and the time range left as `?` placeholders for
+/// `bind_dashboard_query`.
+///
+/// `TraceCount` is a count of traces per bucket, so `aggregation` is ignored for it.
+pub fn dashboard_query_template(
+    metric: DashboardMetric,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> &'static str {
+    let aggregation = match metric {
+        DashboardMetric::TraceCount => Aggregation::Total,
+        _ => aggregation,
+    };
+    let key = TemplateKey {
+        metric,
+        aggregation,
+        group_by_interval,
+        relative: matches!(time_range, TimeRange::Relative { .. }),
+    };
+
+    // Every key is inserted by `build_dashboard_templates`
+    DASHBOARD_TEMPLATES[&key].as_str()
+}
+
+/// Binds the placeholders of a `dashboard_query_template` in order
+pub fn bind_dashboard_query(
+    query: Query,
+    metric: DashboardMetric,
+    project_id: Uuid,
+    cost_precision: u32,
+    time_range: &TimeRange,
+) -> Query {
+    let mut query = query.bind(project_id);
+    if metric == DashboardMetric::TotalCost {
+        query = query.bind(cost_precision);
+    }
+    match time_range {
+        TimeRange::Relative { past_hours } => query.bind(*past_hours).bind(*past_hours),
+        TimeRange::Absolute { start, end } => query
+            .bind(start.timestamp())
+            .bind(end.timestamp())
+            .bind(start.timestamp())
+            .bind(end.timestamp()),
+    }
+}
+
+fn build_dashboard_templates() -> HashMap<TemplateKey, String> {
+    let mut templates = HashMap::new();
+
+    for group_by_interval in INTERVALS {
+        for relative in [true, false] {
+            templates.insert(
+                TemplateKey {
+                    metric: DashboardMetric::TraceCount,
+                    aggregation: Aggregation::Total,
+                    group_by_interval,
+                    relative,
+                },
+                trace_count_template(group_by_interval, relative),
+            );
+
+            for (metric, column) in NUMERIC_METRICS {
+                for aggregation in AGGREGATIONS {
+                    templates.insert(
+                        TemplateKey {
+                            metric,
+                            aggregation,
+                            group_by_interval,
+                            relative,
+                        },
+                        numeric_template(metric, column, aggregation, group_by_interval, relative),
+                    );
+                }
+            }
+        }
+    }
+
+    templates
+}
+
+/// Time condition on `time` and `GROUP BY time ... WITH FILL` statement, with the bounds
+/// of the time range left as placeholders. Relative ranges take the past hours twice,
+/// absolute ones take the start and end timestamps twice.
+fn time_range_placeholders(group_by_interval: GroupByInterval, relative: bool) -> (String, String) {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let ch_interval = group_by_interval.to_interval();
+    let ch_step = group_by_interval.to_ch_step();
+
+    let (time_condition, fill_from, fill_to) = if relative {
+        (
+            "time >= now() - INTERVAL ? HOUR".to_string(),
+            format!("{ch_round_time}(NOW() - INTERVAL ? HOUR + INTERVAL {ch_interval})"),
+            format!("{ch_round_time}(NOW() + INTERVAL {ch_interval})"),
+        )
+    } else {
+        (
+            "time >= fromUnixTimestamp(?)
+        AND time <= fromUnixTimestamp(?)"
+                .to_string(),
+            format!("{ch_round_time}(fromUnixTimestamp(?))"),
+            format!("{ch_round_time}(fromUnixTimestamp(?) + INTERVAL {ch_interval})"),
+        )
+    };
+
+    let group_by_time_statement = format!(
+        "GROUP BY
+            time
+        ORDER BY
+            time
+        WITH FILL
+        FROM {fill_from}
+        TO {fill_to}
+        STEP {ch_step}"
+    );
+
+    (time_condition, group_by_time_statement)
+}
+
+fn trace_count_template(group_by_interval: GroupByInterval, relative: bool) -> String {
+    let table = SpanTable::default();
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let (time_condition, group_by_time_statement) =
+        time_range_placeholders(group_by_interval, relative);
+    // The same count as `build_trace_count_metric_query`, which the template stands in for
+    let ch_count = CountMode::Exact.to_ch_count_distinct("trace_id");
+
+    format!(
+        "
+    WITH traces AS (
+        SELECT
+            trace_id,
+            project_id,
+            {ch_round_time}(MIN(start_time)) as time
+        FROM {table}
+        WHERE project_id = ?
+        GROUP BY project_id, trace_id
+    )
+    SELECT
+        time,
+        toInt64({ch_count}) as value
+    FROM traces
+    WHERE {time_condition}
+    {group_by_time_statement}"
+    )
+}
+
+fn numeric_template(
+    metric: DashboardMetric,
+    column: NumericColumn,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    relative: bool,
+) -> String {
+    let ch_aggregation = aggregation.to_ch_agg_function();
+    let value = match metric {
+        DashboardMetric::TotalCost => format!("round(ifNull({ch_aggregation}(value), 0), ?)"),
+        _ => format!("ifNull({ch_aggregation}(value), 0)"),
+    };
+    let expression = match metric {
+        DashboardMetric::TraceLatencySeconds => {
+            NegativeLatencyPolicy::default().to_ch_latency(column.to_ch_trace_expression())
+        }
+        _ => column.to_ch_trace_expression().to_string(),
+    };
+    let traces_cte = per_trace_cte(
+        &SpanTable::default(),
+        group_by_interval,
+        &format!("toFloat64({expression})"),
+        &["project_id = ?".to_string()],
+    );
+    let (time_condition, group_by_time_statement) =
+        time_range_placeholders(group_by_interval, relative);
+
+    format!(
+        "
+    WITH {traces_cte}
+    SELECT
+        time,
+        {value} as value
+    FROM traces
+    WHERE {time_condition}
+    {group_by_time_statement}"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ch::spans::build_trace_count_metric_query;
+
+    #[test]
+    fn test_templates_placeholders_match_binds() {
+        let relative = TimeRange::Relative { past_hours: 24 };
+        let absolute = TimeRange::Absolute {
+            start: chrono::Utc::now() - chrono::Duration::hours(24),
+            end: chrono::Utc::now(),
+        };
+        let metrics = [
+            DashboardMetric::TraceCount,
+            DashboardMetric::TraceLatencySeconds,
+            DashboardMetric::TotalTokens,
+            DashboardMetric::TotalCost,
+        ];
+
+        for metric in metrics {
+            for aggregation in AGGREGATIONS {
+                for group_by_interval in INTERVALS {
+                    let cost_placeholders = usize::from(metric == DashboardMetric::TotalCost);
+
+                    let template =
+                        dashboard_query_template(metric, aggregation, group_by_interval, &relative);
+                    assert_eq!(template.matches('?').count(), 3 + cost_placeholders);
+                    assert!(template.contains("project_id = ?"));
+
+                    let template =
+                        dashboard_query_template(metric, aggregation, group_by_interval, &absolute);
+                    assert_eq!(template.matches('?').count(), 5 + cost_placeholders);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_trace_count_template_counts_like_builder() {
+        let time_range = TimeRange::Relative { past_hours: 24 };
+        let ch_count = format!(
+            "toInt64({}) as value",
+            CountMode::Exact.to_ch_count_distinct("trace_id")
+        );
+
+        let template = dashboard_query_template(
+            DashboardMetric::TraceCount,
+            Aggregation::Total,
+            GroupByInterval::Hour,
+            &time_range,
+        );
+        let query = build_trace_count_metric_query(
+            &SpanTable::default(),
+            GroupByInterval::Hour,
+            Uuid::nil(),
+            &time_range,
+            1,
+            CountMode::Exact,
+        );
+
+        assert!(template.contains(&ch_count));
+        assert!(query.contains(&ch_count));
+    }
+}
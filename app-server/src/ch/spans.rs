@@ -1,29 +1,117 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
 use anyhow::Result;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Utc};
 use clickhouse::Row;
+use futures::{Stream, StreamExt};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use uuid::Uuid;
 
-use crate::{db, traces::SpanUsage};
+use crate::{
+    db,
+    db::trace::SpanAttributes,
+    opentelemetry::opentelemetry_proto_trace_v1::Span as OtelSpan,
+    traces::{
+        attributes::{GEN_AI_INPUT_COST, GEN_AI_OUTPUT_COST, GEN_AI_TOTAL_COST},
+        SpanUsage,
+    },
+};
 
 use super::{
-    modifiers::GroupByInterval,
+    metric_query::MetricQuery,
+    modifiers::{to_ch_rounded_cost, FxRate, GroupByInterval, NegativeLatencyPolicy, TimeRange},
+    query_templates::{bind_dashboard_query, dashboard_query_template, DashboardMetric},
     utils::{
-        chrono_to_nanoseconds, execute_query, group_by_time_absolute_statement,
-        group_by_time_relative_statement,
+        chrono_to_nanoseconds, execute_project_query, execute_query, nanoseconds_to_chrono,
+        require_column, round_small_values_to_zero, validate_identifier, QueryOptions,
+        PROJECT_IDS_CONDITION,
     },
-    Aggregation, MetricTimeValue,
+    Aggregation, CountMode, FloatMetricTimeValue, IntMetricTimeValue, MetricTimeValue,
+    ModelFloatMetricTimeValue, ModelMetricTimeValue, MultiMetricTimeValue, NameMetricTimeValue,
+    ProjectMetricTimeValue, ProviderMetricTimeValue, SessionMetricTimeValue,
 };
 
-#[derive(Row, Serialize, Deserialize)]
+/// Name of the ClickHouse table spans are written to and read from.
+///
+/// The name is interpolated into SQL, so it is validated to be a plain identifier.
+#[derive(Clone, Debug)]
+pub struct SpanTable(String);
+
+impl SpanTable {
+    pub fn new(name: &str) -> Result<Self> {
+        validate_identifier(name)?;
+        Ok(Self(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Table spans are inserted into, read from `CLICKHOUSE_SPANS_INSERT_TABLE`, or the
+    /// default table if it is not set.
+    ///
+    /// On a sharded cluster this is the `Distributed` table created by
+    /// `distributed_span_table_ddl`, which routes each span to a shard by its project id,
+    /// so that all spans of a project and their metrics stay on one shard. Setting it to
+    /// the shard-local table instead writes to the shard the client is connected to.
+    pub fn insert_table_from_env() -> Result<Self> {
+        match std::env::var("CLICKHOUSE_SPANS_INSERT_TABLE") {
+            Ok(name) => Self::new(&name),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Statement creating `distributed_table` on every node of `cluster`, with the columns of
+/// `local_table`, the shard-local spans table. Inserts are sharded by the hash of the
+/// project id.
+pub fn distributed_span_table_ddl(
+    cluster: &str,
+    local_table: &SpanTable,
+    distributed_table: &SpanTable,
+) -> Result<String> {
+    validate_identifier(cluster)?;
+
+    Ok(format!(
+        "CREATE TABLE IF NOT EXISTS {distributed_table} ON CLUSTER {cluster}
+        AS {local_table}
+        ENGINE = Distributed({cluster}, currentDatabase(), {local_table}, cityHash64(project_id))"
+    ))
+}
+
+impl Default for SpanTable {
+    fn default() -> Self {
+        Self(String::from("spans"))
+    }
+}
+
+impl fmt::Display for SpanTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Row, Serialize, Deserialize, Clone)]
 pub struct CHSpan {
     #[serde(with = "clickhouse::serde::uuid")]
     pub span_id: Uuid,
     pub name: String,
     pub span_type: u8,
-    /// Start time in nanoseconds
+    /// Start time in nanoseconds. The column itself is a `DateTime64(9)`, i64 nanoseconds
+    /// is only its RowBinary encoding, so queries can truncate it with `toStartOf*`
+    /// directly.
     pub start_time: i64,
-    /// End time in nanoseconds
+    /// End time in nanoseconds, stored like `start_time`
     pub end_time: i64,
     pub prompt_tokens: i64,
     pub completion_tokens: i64,
@@ -39,12 +127,90 @@ pub struct CHSpan {
     pub trace_id: Uuid,
     pub provider: String,
     pub user_id: String,
-    // Default value is <null>  backwards compatibility or if path attribute is not present
+    // NULL_SENTINEL for backwards compatibility or if the path attribute is not present
     pub path: String,
+    /// Nesting depth of the span within its trace, 0 for root spans
+    pub depth: u32,
+    /// Version of the span in nanoseconds, stored like `start_time`. Every insert sets it to
+    /// the insert time, so the spans table, a `ReplacingMergeTree(updated_at)`, keeps the
+    /// latest write of each span, e.g. the completed span over an in-progress one.
+    pub updated_at: i64,
+    /// Span id of the parent span, nil for root spans
+    #[serde(with = "clickhouse::serde::uuid")]
+    pub parent_span_id: Uuid,
+    /// False if the span's model had no pricing, so that its zero costs can be told apart
+    /// from genuinely free calls
+    pub cost_known: bool,
+    /// `ok` or `error`, NULL_SENTINEL if the status was not set
+    pub status: String,
+    /// Span attributes selected by an `AttributeExtractionConfig`. The column is a
+    /// `Map(String, String)`, whose RowBinary encoding is the same as that of an array of
+    /// key-value tuples.
+    pub attributes: Vec<(String, String)>,
+    /// Exporter that wrote the span, `DEFAULT_INGEST_SOURCE` unless set otherwise, so
+    /// that rows can be attributed to the version that produced them
+    pub ingest_source: String,
+    /// Prompt tokens served from the provider's prompt cache, `None` if the provider did
+    /// not report caching
+    pub cached_tokens: Option<i64>,
+}
+
+/// Stored in place of missing string values, since the spans columns are not nullable
+pub const NULL_SENTINEL: &str = "<null>";
+
+/// `ingest_source` of spans written by this server
+pub const DEFAULT_INGEST_SOURCE: &str = concat!("app-server/", env!("CARGO_PKG_VERSION"));
+
+/// Span attribute keys to copy into the `attributes` column of `CHSpan`, e.g. tags such
+/// as a tenant or a feature flag that only some customers set
+#[derive(Clone, Debug, Default)]
+pub struct AttributeExtractionConfig {
+    keys: Vec<String>,
+}
+
+impl AttributeExtractionConfig {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self { keys }
+    }
+
+    /// Reads comma-separated keys from `SPAN_EXTRACTED_ATTRIBUTES`, extracting nothing if
+    /// it is not set
+    pub fn from_env() -> Self {
+        let keys = std::env::var("SPAN_EXTRACTED_ATTRIBUTES")
+            .map(|keys| {
+                keys.split(',')
+                    .map(|key| key.trim().to_string())
+                    .filter(|key| !key.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self { keys }
+    }
+
+    /// Configured attributes present on the span. Non-string values are stored as JSON.
+    fn extract(&self, span_attributes: &SpanAttributes) -> Vec<(String, String)> {
+        self.keys
+            .iter()
+            .filter_map(|key| {
+                let value = match span_attributes.attributes.get(key)? {
+                    Value::Null => return None,
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                Some((key.clone(), value))
+            })
+            .collect()
+    }
 }
 
 impl CHSpan {
-    pub fn from_db_span(span: &db::trace::Span, usage: SpanUsage, project_id: Uuid) -> Self {
+    pub fn from_db_span(
+        span: &db::trace::Span,
+        usage: SpanUsage,
+        project_id: Uuid,
+        attribute_extraction: &AttributeExtractionConfig,
+    ) -> Self {
         let span_attributes = span.get_attributes();
 
         CHSpan {
@@ -62,21 +228,237 @@ impl CHSpan {
             model: usage
                 .response_model
                 .or(usage.request_model)
-                .unwrap_or(String::from("<null>")),
+                .unwrap_or(String::from(NULL_SENTINEL)),
             session_id: span_attributes
                 .session_id()
-                .unwrap_or(String::from("<null>")),
+                .unwrap_or(String::from(NULL_SENTINEL)),
             project_id: project_id,
             trace_id: span.trace_id,
-            provider: usage.provider_name.unwrap_or(String::from("<null>")),
-            user_id: span_attributes.user_id().unwrap_or(String::from("<null>")),
-            path: span_attributes.path().unwrap_or(String::from("<null>")),
+            provider: usage.provider_name.unwrap_or(String::from(NULL_SENTINEL)),
+            user_id: span_attributes
+                .user_id()
+                .unwrap_or(String::from(NULL_SENTINEL)),
+            path: span_attributes
+                .path()
+                .unwrap_or(String::from(NULL_SENTINEL)),
+            depth: span_attributes.depth(),
+            updated_at: chrono_to_nanoseconds(Utc::now()),
+            parent_span_id: span.parent_span_id.unwrap_or(Uuid::nil()),
+            cost_known: usage.cost_known,
+            status: span_attributes
+                .status()
+                .unwrap_or(String::from(NULL_SENTINEL)),
+            attributes: attribute_extraction.extract(&span_attributes),
+            ingest_source: String::from(DEFAULT_INGEST_SOURCE),
+            cached_tokens: span_attributes.cached_tokens(),
+        }
+    }
+
+    /// Maps an OTLP span directly, taking tokens, costs and the model from the
+    /// `gen_ai.usage.*`, `gen_ai.request.model` and `gen_ai.response.model` attributes as
+    /// reported by the instrumentation, without looking up model pricing.
+    ///
+    /// Fails if the trace id is not 16 bytes or the span id is not 8 bytes.
+    pub fn from_otel(
+        otel_span: &OtelSpan,
+        project_id: Uuid,
+        attribute_extraction: &AttributeExtractionConfig,
+    ) -> Result<Self> {
+        if otel_span.trace_id.len() != 16 {
+            return Err(anyhow::anyhow!(
+                "OTLP trace id must be 16 bytes, got {}",
+                otel_span.trace_id.len()
+            ));
+        }
+        if otel_span.span_id.len() != 8 {
+            return Err(anyhow::anyhow!(
+                "OTLP span id must be 8 bytes, got {}",
+                otel_span.span_id.len()
+            ));
+        }
+        if !otel_span.parent_span_id.is_empty() && otel_span.parent_span_id.len() != 8 {
+            return Err(anyhow::anyhow!(
+                "OTLP parent span id must be empty or 8 bytes, got {}",
+                otel_span.parent_span_id.len()
+            ));
+        }
+
+        let span = db::trace::Span::from_otel_span(otel_span.clone());
+        let span_attributes = span.get_attributes();
+
+        let cost = |key: &str| match span_attributes.attributes.get(key) {
+            Some(Value::Number(n)) => n.as_f64(),
+            _ => None,
+        };
+        let input_cost = cost(GEN_AI_INPUT_COST);
+        let output_cost = cost(GEN_AI_OUTPUT_COST);
+        let total_cost = cost(GEN_AI_TOTAL_COST).or(match (input_cost, output_cost) {
+            (None, None) => None,
+            _ => Some(input_cost.unwrap_or(0.0) + output_cost.unwrap_or(0.0)),
+        });
+        let request_model = span_attributes.request_model();
+        let response_model = span_attributes.response_model();
+        let prompt_tokens = span_attributes.prompt_tokens();
+        let completion_tokens = span_attributes.completion_tokens();
+
+        let usage = SpanUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            input_cost: input_cost.unwrap_or(0.0),
+            output_cost: output_cost.unwrap_or(0.0),
+            total_cost: total_cost.unwrap_or(0.0),
+            // Without a model there is nothing to price, as in `get_llm_usage_for_span`
+            cost_known: total_cost.is_some()
+                || (request_model.is_none() && response_model.is_none()),
+            request_model,
+            response_model,
+            provider_name: span_attributes.provider_name(),
+        };
+
+        Ok(Self::from_db_span(
+            &span,
+            usage,
+            project_id,
+            attribute_extraction,
+        ))
+    }
+
+    /// Replaces `NULL_SENTINEL` values with empty strings
+    pub fn strip_sentinels(&mut self) {
+        for value in [
+            &mut self.model,
+            &mut self.session_id,
+            &mut self.provider,
+            &mut self.user_id,
+            &mut self.path,
+            &mut self.status,
+            &mut self.ingest_source,
+        ] {
+            if value.as_str() == NULL_SENTINEL {
+                value.clear();
+            }
+        }
+    }
+}
+
+fn strip_span_sentinels(spans: &mut [CHSpan], options: &QueryOptions) {
+    if options.strip_sentinels {
+        spans.iter_mut().for_each(CHSpan::strip_sentinels);
+    }
+}
+
+struct InsertCounters {
+    spans_inserted: AtomicU64,
+    insert_errors: AtomicU64,
+    retries: AtomicU64,
+    spans_dropped: AtomicU64,
+}
+
+impl InsertCounters {
+    const fn new() -> Self {
+        Self {
+            spans_inserted: AtomicU64::new(0),
+            insert_errors: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            spans_dropped: AtomicU64::new(0),
         }
     }
 }
 
-pub async fn insert_span(clickhouse: clickhouse::Client, span: &CHSpan) -> Result<()> {
-    let ch_insert = clickhouse.insert("spans");
+static INSERT_COUNTERS: InsertCounters = InsertCounters::new();
+
+/// Snapshot of the span insertion counters, accumulated since the process started
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct InsertStats {
+    pub spans_inserted: u64,
+    pub insert_errors: u64,
+    pub retries: u64,
+    pub spans_dropped: u64,
+}
+
+pub fn insert_stats() -> InsertStats {
+    InsertStats {
+        spans_inserted: INSERT_COUNTERS.spans_inserted.load(Ordering::Relaxed),
+        insert_errors: INSERT_COUNTERS.insert_errors.load(Ordering::Relaxed),
+        retries: INSERT_COUNTERS.retries.load(Ordering::Relaxed),
+        spans_dropped: INSERT_COUNTERS.spans_dropped.load(Ordering::Relaxed),
+    }
+}
+
+fn record_insert_success(span_count: u64) {
+    INSERT_COUNTERS
+        .spans_inserted
+        .fetch_add(span_count, Ordering::Relaxed);
+}
+
+fn record_insert_failure(span_count: u64) {
+    INSERT_COUNTERS
+        .insert_errors
+        .fetch_add(1, Ordering::Relaxed);
+    INSERT_COUNTERS
+        .spans_dropped
+        .fetch_add(span_count, Ordering::Relaxed);
+}
+
+fn record_insert_retry() {
+    INSERT_COUNTERS.retries.fetch_add(1, Ordering::Relaxed);
+}
+
+pub async fn insert_span(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    span: &CHSpan,
+) -> Result<()> {
+    let span = CHSpan {
+        updated_at: chrono_to_nanoseconds(Utc::now()),
+        ..span.clone()
+    };
+    let res = write_span(clickhouse, table, &span).await;
+    match res {
+        Ok(_) => record_insert_success(1),
+        Err(_) => record_insert_failure(1),
+    }
+    res
+}
+
+/// Converts `span` with `CHSpan::from_db_span` and inserts it
+pub async fn insert_db_span(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    span: &db::trace::Span,
+    usage: SpanUsage,
+    project_id: Uuid,
+    attribute_extraction: &AttributeExtractionConfig,
+) -> Result<()> {
+    let ch_span = CHSpan::from_db_span(span, usage, project_id, attribute_extraction);
+    insert_span(clickhouse, table, &ch_span).await
+}
+
+/// Spans starting further than this in the future are rejected on insert. Clients with
+/// skewed clocks send such spans, and they would show up in `now()`-relative queries
+/// indefinitely.
+pub const MAX_FUTURE_START_SKEW_SECONDS: i64 = 24 * 60 * 60;
+
+fn validate_span_time(span: &CHSpan) -> Result<()> {
+    let max_start_time = Utc::now() + chrono::Duration::seconds(MAX_FUTURE_START_SKEW_SECONDS);
+    if span.start_time > chrono_to_nanoseconds(max_start_time) {
+        return Err(anyhow::anyhow!(
+            "Span {} starts at {}, too far in the future",
+            span.span_id,
+            nanoseconds_to_chrono(span.start_time)
+        ));
+    }
+    Ok(())
+}
+
+async fn write_span(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    span: &CHSpan,
+) -> Result<()> {
+    validate_span_time(span)?;
+    let ch_insert = clickhouse.insert(table.as_str());
     match ch_insert {
         Ok(mut ch_insert) => {
             ch_insert.write(span).await?;
@@ -97,264 +479,5117 @@ pub async fn insert_span(clickhouse: clickhouse::Client, span: &CHSpan) -> Resul
     }
 }
 
-pub async fn get_total_trace_count_metrics_relative(
-    clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    past_hours: i64,
-) -> Result<Vec<MetricTimeValue<i64>>> {
-    let ch_round_time = group_by_interval.to_ch_truncate_time();
+/// Columns of `CHSpan` holding nanoseconds that are DateTime64 in the table
+const NANOSECOND_COLUMNS: &[&str] = &["start_time", "end_time", "updated_at"];
 
-    let query_string = format!(
-        "
-    WITH traces AS (
-        SELECT
-            trace_id,
-            project_id,
-            {ch_round_time}(MIN(start_time)) as time
-        FROM spans
-        GROUP BY project_id, trace_id
-    )
-    SELECT
-        time,
-        COUNT(DISTINCT(trace_id)) as value
-    FROM traces
-    WHERE
-        project_id = '{project_id}'
-        AND time >= now() - INTERVAL {past_hours} HOUR
-    {}",
-        group_by_time_relative_statement(past_hours, group_by_interval)
-    );
+/// Columns of `CHSpan` holding key-value pairs that are `Map(String, String)` in the table
+const MAP_COLUMNS: &[&str] = &["attributes"];
 
-    execute_query(&clickhouse, &query_string).await
+fn split_map_entries(entries: Value) -> (Value, Value) {
+    let (keys, values): (Vec<Value>, Vec<Value>) = match entries {
+        Value::Array(entries) => entries
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Value::Array(mut pair) if pair.len() == 2 => {
+                    let value = pair.pop()?;
+                    let key = pair.pop()?;
+                    Some((key, value))
+                }
+                _ => None,
+            })
+            .unzip(),
+        _ => (Vec::new(), Vec::new()),
+    };
+
+    (Value::Array(keys), Value::Array(values))
 }
 
-pub async fn get_total_trace_count_metrics_absolute(
+/// Inserts a span together with optional columns that `CHSpan` doesn't have, e.g. ones
+/// only some tables or callers populate. Null values, in `extra` or in the span such as a
+/// missing `cached_tokens`, are skipped, so those columns get their table defaults. Only
+/// string, number and boolean values are supported in `extra`.
+pub async fn insert_span_with_extra(
     clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-) -> Result<Vec<MetricTimeValue<i64>>> {
-    let ch_round_time = group_by_interval.to_ch_truncate_time();
-    let ch_start_time = start_time.timestamp();
-    let ch_end_time = end_time.timestamp();
+    table: &SpanTable,
+    span: &CHSpan,
+    extra: &HashMap<String, Value>,
+) -> Result<()> {
+    let res = write_span_with_extra(clickhouse, table, span, extra).await;
+    match res {
+        Ok(_) => record_insert_success(1),
+        Err(_) => record_insert_failure(1),
+    }
+    res
+}
 
-    let query_string = format!(
-        "
-    WITH traces AS (
-    SELECT
-        trace_id,
-        project_id,
-        {ch_round_time}(MIN(start_time)) as time,
-        SUM(total_tokens) as value
-    FROM spans
-    GROUP BY project_id, trace_id
-    )
-    SELECT
-        time,
-        COUNT(DISTINCT(trace_id)) as value
-    FROM traces
-    WHERE
-        project_id = '{project_id}'
-        AND time >= fromUnixTimestamp({ch_start_time})
-        AND time <= fromUnixTimestamp({ch_end_time})
-    {}",
-        group_by_time_absolute_statement(start_time, end_time, group_by_interval)
-    );
+async fn write_span_with_extra(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    span: &CHSpan,
+    extra: &HashMap<String, Value>,
+) -> Result<()> {
+    validate_span_time(span)?;
+    let columns = insert_columns(span, extra, chrono_to_nanoseconds(Utc::now()))?;
+
+    let mut query = clickhouse.query(&format!(
+        "INSERT INTO {table} ({}) VALUES ({})",
+        columns.names, columns.placeholders
+    ));
+    for value in &columns.binds {
+        query = query.bind(value);
+    }
 
-    execute_query(&clickhouse, &query_string).await
+    query
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Clickhouse span insertion failed: {:?}", e))
 }
 
-pub async fn get_trace_latency_seconds_metrics_relative(
-    clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    past_hours: i64,
-    aggregation: Aggregation,
-) -> Result<Vec<MetricTimeValue<f64>>> {
-    let query_string = span_metric_query_relative(
-        project_id,
-        group_by_interval,
-        past_hours,
-        aggregation,
-        "(toUnixTimestamp64Nano(MAX(end_time)) - toUnixTimestamp64Nano(MIN(start_time))) / 1e9",
-    );
+/// Column list, placeholders and their values of an `INSERT ... VALUES` statement
+struct InsertColumns {
+    names: String,
+    placeholders: String,
+    binds: Vec<Value>,
+}
+
+/// Columns of `span` and `extra` to insert, with `updated_at` set to the given version.
+/// Null values are left out, whether they come from the span or from `extra`, so that
+/// those columns get their table defaults.
+fn insert_columns(
+    span: &CHSpan,
+    extra: &HashMap<String, Value>,
+    updated_at: i64,
+) -> Result<InsertColumns> {
+    // Uuids serialize to their string form for human readable formats such as JSON
+    let Value::Object(mut columns) = serde_json::to_value(span)? else {
+        return Err(anyhow::anyhow!("Span did not serialize to an object"));
+    };
+    columns.retain(|_, value| !value.is_null());
+    columns.insert(String::from("updated_at"), Value::from(updated_at));
+
+    for (name, value) in extra {
+        if value.is_null() {
+            continue;
+        }
+        validate_identifier(name)?;
+        if !(value.is_string() || value.is_number() || value.is_boolean()) {
+            return Err(anyhow::anyhow!("Unsupported value for column {}", name));
+        }
+        if columns.insert(name.clone(), value.clone()).is_some() {
+            return Err(anyhow::anyhow!(
+                "Column {} is already set by the span",
+                name
+            ));
+        }
+    }
+
+    let names = columns.keys().cloned().collect::<Vec<String>>().join(", ");
+    let mut placeholders = Vec::with_capacity(columns.len());
+    let mut binds = Vec::with_capacity(columns.len());
+    for (name, value) in columns {
+        if NANOSECOND_COLUMNS.contains(&name.as_str()) {
+            placeholders.push("fromUnixTimestamp64Nano(?)");
+            binds.push(value);
+        } else if MAP_COLUMNS.contains(&name.as_str()) {
+            // Key-value pairs serialize to nested arrays, which don't cast to a Map
+            let (keys, values) = split_map_entries(value);
+            placeholders.push("mapFromArrays(?, ?)");
+            binds.push(keys);
+            binds.push(values);
+        } else {
+            placeholders.push("?");
+            binds.push(value);
+        }
+    }
 
-    execute_query(&clickhouse, &query_string).await
+    Ok(InsertColumns {
+        names,
+        placeholders: placeholders.join(", "),
+        binds,
+    })
 }
 
-pub async fn get_trace_latency_seconds_metrics_absolute(
+/// Upper limit on the number of spans returned for a single trace
+const MAX_SPANS_PER_TRACE: u64 = 10_000;
+
+/// Spans per chunk when a batch is split across connections in `insert_spans_parallel`
+const PARALLEL_INSERT_CHUNK_SIZE: usize = 10_000;
+
+pub async fn insert_spans(
     clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-    aggregation: Aggregation,
-) -> Result<Vec<MetricTimeValue<f64>>> {
-    let query_string = span_metric_query_absolute(
-        project_id,
-        group_by_interval,
-        start_time,
-        end_time,
-        aggregation,
-        "(toUnixTimestamp64Nano(MAX(end_time)) - toUnixTimestamp64Nano(MIN(start_time))) / 1e9",
-    );
+    table: &SpanTable,
+    spans: &[CHSpan],
+) -> Result<()> {
+    if spans.is_empty() {
+        return Ok(());
+    }
 
-    execute_query(&clickhouse, &query_string).await
+    let res = write_spans(clickhouse, table, spans).await;
+    match res {
+        Ok(_) => record_insert_success(spans.len() as u64),
+        Err(_) => record_insert_failure(spans.len() as u64),
+    }
+    res
 }
 
-pub async fn get_total_token_count_metrics_relative(
+/// Outcome of `try_insert_spans`
+pub struct InsertReport<'a> {
+    pub succeeded: usize,
+    /// Spans that could not be inserted even on their own, with the error of their last
+    /// attempt, so that callers can re-queue only these
+    pub failed: Vec<(&'a CHSpan, anyhow::Error)>,
+}
+
+/// Inserts `spans`, splitting a failed batch in halves and retrying each half until the
+/// failing spans are isolated. Unlike `insert_spans`, a failure does not lose track of
+/// which spans made it, which at-least-once ingestion relies on.
+///
+/// An insert is only atomic within one partition, so a retried batch spanning several
+/// partitions may write some spans twice. Those duplicates collapse on a
+/// `ReplacingMergeTree` table, see `get_latest_span_by_id`.
+pub async fn try_insert_spans<'a>(
     clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    past_hours: i64,
-    aggregation: Aggregation,
-) -> Result<Vec<MetricTimeValue<i64>>> {
-    let query_string = span_metric_query_relative(
-        project_id,
-        group_by_interval,
-        past_hours,
-        aggregation,
-        "SUM(total_tokens)",
-    );
+    table: &SpanTable,
+    spans: &'a [CHSpan],
+) -> InsertReport<'a> {
+    let mut report = InsertReport {
+        succeeded: 0,
+        failed: Vec::new(),
+    };
+    let mut pending = vec![spans];
 
-    let mut cursor = clickhouse
-        .query(&query_string)
-        .fetch::<MetricTimeValue<i64>>()?;
+    while let Some(chunk) = pending.pop() {
+        if chunk.is_empty() {
+            continue;
+        }
 
-    let mut res = Vec::new();
-    while let Some(row) = cursor.next().await? {
-        res.push(row);
+        match write_spans(clickhouse.clone(), table, chunk).await {
+            Ok(_) => {
+                record_insert_success(chunk.len() as u64);
+                report.succeeded += chunk.len();
+            }
+            Err(e) if chunk.len() == 1 => {
+                record_insert_failure(1);
+                report.failed.push((&chunk[0], e));
+            }
+            Err(_) => {
+                record_insert_retry();
+                let (first, second) = chunk.split_at(chunk.len() / 2);
+                // Popped in order, so spans keep being written in their original order
+                pending.push(second);
+                pending.push(first);
+            }
+        }
     }
 
-    Ok(res)
+    report
 }
 
-pub async fn get_total_token_count_metrics_absolute(
+async fn write_spans<'a>(
     clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-    aggregation: Aggregation,
-) -> Result<Vec<MetricTimeValue<i64>>> {
-    let query_string = span_metric_query_absolute(
-        project_id,
-        group_by_interval,
-        start_time,
-        end_time,
-        aggregation,
-        "SUM(total_tokens)",
-    );
+    table: &SpanTable,
+    spans: impl IntoIterator<Item = &'a CHSpan>,
+) -> Result<()> {
+    let ch_insert = clickhouse.insert(table.as_str());
+    match ch_insert {
+        Ok(mut ch_insert) => {
+            let updated_at = chrono_to_nanoseconds(Utc::now());
+            for span in spans {
+                // Dropping the insert before `end` aborts it, so nothing is written
+                validate_span_time(span)?;
+                ch_insert
+                    .write(&CHSpan {
+                        updated_at,
+                        ..span.clone()
+                    })
+                    .await?;
+            }
+            let ch_insert_end_res = ch_insert.end().await;
+            match ch_insert_end_res {
+                Ok(_) => Ok(()),
+                Err(e) => Err(anyhow::anyhow!(
+                    "Clickhouse spans insertion failed: {:?}",
+                    e
+                )),
+            }
+        }
+        Err(e) => {
+            return Err(anyhow::anyhow!(
+                "Failed to insert spans into Clickhouse: {:?}",
+                e
+            ));
+        }
+    }
+}
 
-    execute_query(&clickhouse, &query_string).await
+/// How `insert_spans_by_partition` groups a batch before inserting it. Grouping doesn't
+/// reduce the number of parts, since an insert already writes one part per partition it
+/// touches, but it bounds what a failed insert takes down with it to one group.
+///
+/// The shipped spans table has no `PARTITION BY`, so every grouping other than `None`
+/// only adds inserts there. The groupings are meant for tables partitioned by the same
+/// key, e.g. `PARTITION BY toYYYYMM(start_time)` for `Month`.
+#[derive(Clone, Copy, Default)]
+pub enum InsertPartitioning {
+    /// The whole batch is written as one insert
+    #[default]
+    None,
+    /// `toYYYYMM(start_time)`
+    Month,
+    /// `project_id`
+    Project,
+    /// `(project_id, toYYYYMM(start_time))`
+    ProjectMonth,
 }
 
-pub async fn get_cost_usd_metrics_relative(
-    clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    past_hours: i64,
-    aggregation: Aggregation,
-) -> Result<Vec<MetricTimeValue<f64>>> {
-    let query_string = span_metric_query_relative(
-        project_id,
-        group_by_interval,
-        past_hours,
-        aggregation,
-        "SUM(total_cost)",
-    );
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct PartitionKey {
+    project_id: Option<Uuid>,
+    month: Option<(i32, u32)>,
+}
+
+impl InsertPartitioning {
+    fn partition_key(&self, span: &CHSpan) -> PartitionKey {
+        let month = || {
+            let start_time = nanoseconds_to_chrono(span.start_time);
+            (start_time.year(), start_time.month())
+        };
 
-    execute_query(&clickhouse, &query_string).await
+        match self {
+            InsertPartitioning::None => PartitionKey {
+                project_id: None,
+                month: None,
+            },
+            InsertPartitioning::Month => PartitionKey {
+                project_id: None,
+                month: Some(month()),
+            },
+            InsertPartitioning::Project => PartitionKey {
+                project_id: Some(span.project_id),
+                month: None,
+            },
+            InsertPartitioning::ProjectMonth => PartitionKey {
+                project_id: Some(span.project_id),
+                month: Some(month()),
+            },
+        }
+    }
 }
 
-pub async fn get_cost_usd_metrics_absolute(
+/// Like `insert_spans`, but writes one insert per partition group. Groups are written
+/// one after another and a failed group does not stop the others; the first error is
+/// returned once all groups were attempted.
+pub async fn insert_spans_by_partition(
     clickhouse: clickhouse::Client,
-    group_by_interval: GroupByInterval,
-    project_id: Uuid,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-    aggregation: Aggregation,
-) -> Result<Vec<MetricTimeValue<f64>>> {
-    let query_string = span_metric_query_absolute(
-        project_id,
-        group_by_interval,
-        start_time,
-        end_time,
-        aggregation,
-        "SUM(total_cost)",
-    );
+    table: &SpanTable,
+    spans: &[CHSpan],
+    partitioning: InsertPartitioning,
+) -> Result<()> {
+    if let InsertPartitioning::None = partitioning {
+        return insert_spans(clickhouse, table, spans).await;
+    }
 
-    execute_query(&clickhouse, &query_string).await
-}
+    let mut groups: BTreeMap<PartitionKey, Vec<&CHSpan>> = BTreeMap::new();
+    for span in spans {
+        groups
+            .entry(partitioning.partition_key(span))
+            .or_default()
+            .push(span);
+    }
 
-fn span_metric_query_relative(
-    project_id: Uuid,
-    group_by_interval: GroupByInterval,
-    past_hours: i64,
+    let mut first_error = None;
+    for group in groups.into_values() {
+        let span_count = group.len() as u64;
+        match write_spans(clickhouse.clone(), table, group).await {
+            Ok(_) => record_insert_success(span_count),
+            Err(e) => {
+                record_insert_failure(span_count);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub struct ChunkInsertResult {
+    pub chunk_index: usize,
+    pub span_count: usize,
+    /// `None` if the chunk was inserted successfully
+    pub error: Option<anyhow::Error>,
+}
+
+#[derive(Default)]
+pub struct ParallelInsertResult {
+    /// Per-chunk results, ordered by chunk index
+    pub chunks: Vec<ChunkInsertResult>,
+}
+
+impl ParallelInsertResult {
+    pub fn is_success(&self) -> bool {
+        self.chunks.iter().all(|chunk| chunk.error.is_none())
+    }
+
+    pub fn succeeded_span_count(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|chunk| chunk.error.is_none())
+            .map(|chunk| chunk.span_count)
+            .sum()
+    }
+
+    pub fn failed_span_count(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|chunk| chunk.error.is_some())
+            .map(|chunk| chunk.span_count)
+            .sum()
+    }
+}
+
+/// Consumes `stream` and inserts its spans in batches of `batch_size`, e.g. between an
+/// OTLP receiver and ClickHouse. A partial batch is flushed once `flush_interval` has
+/// passed since the last flush, so a quiet source doesn't hold spans back, and when the
+/// stream ends. A failed batch is logged and counted without stopping the stream.
+///
+/// Returns the stats of this stream only; the process-wide counters are updated too.
+pub async fn insert_span_stream<S>(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    stream: S,
+    batch_size: usize,
+    flush_interval: Duration,
+) -> Result<InsertStats>
+where
+    S: Stream<Item = CHSpan>,
+{
+    if batch_size == 0 {
+        return Err(anyhow::anyhow!("Batch size must be positive"));
+    }
+    if flush_interval.is_zero() {
+        return Err(anyhow::anyhow!("Flush interval must be positive"));
+    }
+
+    let mut stats = InsertStats::default();
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut stream = std::pin::pin!(stream);
+    let mut flush_timer = tokio::time::interval(flush_interval);
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    flush_timer.tick().await;
+
+    loop {
+        tokio::select! {
+            span = stream.next() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < batch_size {
+                        continue;
+                    }
+                }
+                None => break,
+            },
+            _ = flush_timer.tick() => {
+                if batch.is_empty() {
+                    continue;
+                }
+            }
+        }
+
+        flush_span_batch(clickhouse.clone(), table, &mut batch, &mut stats).await;
+        flush_timer.reset();
+    }
+    flush_span_batch(clickhouse, table, &mut batch, &mut stats).await;
+
+    Ok(stats)
+}
+
+async fn flush_span_batch(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    batch: &mut Vec<CHSpan>,
+    stats: &mut InsertStats,
+) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let span_count = batch.len() as u64;
+    match insert_spans(clickhouse, table, batch).await {
+        Ok(_) => stats.spans_inserted += span_count,
+        Err(e) => {
+            log::error!("Failed to insert span batch from stream: {:?}", e);
+            stats.insert_errors += 1;
+            stats.spans_dropped += span_count;
+        }
+    }
+    batch.clear();
+}
+
+/// Splits `spans` into chunks and inserts them concurrently, distributing the chunks
+/// round-robin over `clients`. A failed chunk does not fail the whole batch; check
+/// the per-chunk results instead.
+pub async fn insert_spans_parallel(
+    clients: &[clickhouse::Client],
+    table: &SpanTable,
+    spans: Vec<CHSpan>,
+    concurrency: usize,
+) -> Result<ParallelInsertResult> {
+    if clients.is_empty() {
+        return Err(anyhow::anyhow!(
+            "At least one Clickhouse client is required for parallel insertion"
+        ));
+    }
+    if spans.is_empty() {
+        return Ok(ParallelInsertResult::default());
+    }
+
+    let mut chunks = futures::stream::iter(spans.chunks(PARALLEL_INSERT_CHUNK_SIZE).enumerate())
+        .map(|(chunk_index, chunk)| {
+            let clickhouse = clients[chunk_index % clients.len()].clone();
+            async move {
+                let res = insert_spans(clickhouse, table, chunk).await;
+                if let Err(e) = &res {
+                    log::error!("Failed to insert span chunk {}: {:?}", chunk_index, e);
+                }
+                ChunkInsertResult {
+                    chunk_index,
+                    span_count: chunk.len(),
+                    error: res.err(),
+                }
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    chunks.sort_by_key(|chunk| chunk.chunk_index);
+
+    Ok(ParallelInsertResult { chunks })
+}
+
+/// Whether `upsert_span` created the span or replaced an existing one
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsertOutcome {
+    Inserted,
+    Updated,
+}
+
+#[derive(Row, Deserialize)]
+struct TableEngine {
+    engine: String,
+}
+
+lazy_static! {
+    /// Engines of the tables `upsert_span` has written to. Changing an engine means
+    /// rebuilding the table, so a server running during such a migration must be restarted.
+    static ref TABLE_ENGINES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+async fn is_replacing_table(clickhouse: &clickhouse::Client, table: &SpanTable) -> Result<bool> {
+    let cached = TABLE_ENGINES.lock().unwrap().get(table.as_str()).cloned();
+    let engine = match cached {
+        Some(engine) => engine,
+        None => {
+            let table_engine = clickhouse
+                .query(
+                    "SELECT engine FROM system.tables
+                    WHERE database = currentDatabase() AND name = ?",
+                )
+                .bind(table.as_str())
+                .fetch_one::<TableEngine>()
+                .await?;
+            TABLE_ENGINES
+                .lock()
+                .unwrap()
+                .insert(table.as_str().to_string(), table_engine.engine.clone());
+            table_engine.engine
+        }
+    };
+
+    Ok(engine.contains("ReplacingMergeTree"))
+}
+
+#[derive(Row, Deserialize)]
+struct SpanStartTime {
+    start_time: i64,
+}
+
+/// Writes `span`, replacing any existing row with the same `span_id` in its project,
+/// e.g. for late status or cost corrections.
+///
+/// On a `ReplacingMergeTree` the span is simply inserted with a newer `updated_at`, and
+/// older versions disappear when parts are merged, so readers see them until then unless
+/// they read with `FINAL` (see `get_latest_span_by_id`). Rows only replace each other if
+/// their whole sorting key matches, so a correction that moves `start_time` is handled
+/// like on other tables: a lightweight `DELETE` of the existing row followed by the
+/// insert. The span is missing between the two, and concurrent upserts of the same span
+/// may leave either version, so this is meant for occasional corrections rather than for
+/// the ingestion path.
+///
+/// The outcome is decided by an existence check before writing, so a concurrent insert
+/// of the same span may be reported as `Inserted`.
+pub async fn upsert_span(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    span: &CHSpan,
+) -> Result<UpsertOutcome> {
+    let existing = clickhouse
+        .query(&format!(
+            "SELECT DISTINCT start_time FROM {table} WHERE project_id = ? AND span_id = ?"
+        ))
+        .bind(span.project_id)
+        .bind(span.span_id)
+        .fetch_all::<SpanStartTime>()
+        .await?;
+    let outcome = if existing.is_empty() {
+        UpsertOutcome::Inserted
+    } else {
+        UpsertOutcome::Updated
+    };
+
+    let moves_start_time = existing.iter().any(|row| row.start_time != span.start_time);
+    if outcome == UpsertOutcome::Updated
+        && (moves_start_time || !is_replacing_table(&clickhouse, table).await?)
+    {
+        clickhouse
+            .query(&format!(
+                "DELETE FROM {table} WHERE project_id = ? AND span_id = ?"
+            ))
+            .bind(span.project_id)
+            .bind(span.span_id)
+            .execute()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete span before upsert: {:?}", e))?;
+    }
+
+    insert_span(clickhouse, table, span).await?;
+
+    Ok(outcome)
+}
+
+/// Merges the parts of the spans table with `OPTIMIZE TABLE ... FINAL`.
+///
+/// This rewrites the whole table and is expensive, so it should be scheduled off-peak,
+/// e.g. after large deletes or backfills. With `dedup`, fully duplicated rows are removed.
+pub async fn optimize_table(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    dedup: bool,
+) -> Result<()> {
+    let query_string = if dedup {
+        format!("OPTIMIZE TABLE {table} FINAL DEDUPLICATE")
+    } else {
+        format!("OPTIMIZE TABLE {table} FINAL")
+    };
+
+    clickhouse
+        .query(&query_string)
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to optimize spans table: {:?}", e))
+}
+
+#[derive(Row, Deserialize)]
+struct SpanCount {
+    count: u64,
+}
+
+/// Number of spans stored for the project.
+///
+/// With `approximate`, the count is ClickHouse's estimate from the primary index (`EXPLAIN
+/// ESTIMATE`), which only reads index marks and is rounded to whole granules. The row
+/// counts in `system.parts` would be cheaper still, but are not broken down by project.
+pub async fn count_project_spans(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    approximate: bool,
+) -> Result<u64> {
+    let query_string = if approximate {
+        format!(
+            "SELECT toUInt64(SUM(rows)) AS count
+            FROM (EXPLAIN ESTIMATE SELECT count() FROM {table} WHERE project_id = ?)"
+        )
+    } else {
+        format!("SELECT count() AS count FROM {table} WHERE project_id = ?")
+    };
+
+    let query = options
+        .apply(&clickhouse)
+        .query(&query_string)
+        .bind(project_id);
+    let row = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_optional::<SpanCount>().await?)
+        })
+        .await?;
+
+    Ok(row.map(|row| row.count).unwrap_or(0))
+}
+
+#[derive(Row, Deserialize)]
+struct StorageBytes {
+    bytes: u64,
+}
+
+/// Estimated bytes on disk of a project's spans, e.g. for billing and capacity planning.
+///
+/// The spans table is not partitioned by project, so parts hold many projects and their
+/// sizes can't be attributed exactly. Instead, the compressed size of all active parts
+/// is split in proportion to the project's share of rows. Projects whose spans compress
+/// better or worse than average are over- or underestimated accordingly.
+pub async fn get_project_storage_bytes(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+) -> Result<u64> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "WITH
+                (
+                    SELECT sum(bytes_on_disk) FROM system.parts
+                    WHERE database = currentDatabase() AND table = ? AND active
+                ) AS table_bytes,
+                (SELECT count() FROM {table}) AS table_rows,
+                (SELECT count() FROM {table} WHERE project_id = ?) AS project_rows
+            SELECT toUInt64(
+                if(table_rows = 0, 0, table_bytes * (project_rows / table_rows))
+            ) AS bytes"
+        ))
+        .bind(table.as_str())
+        .bind(project_id);
+    let row = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_one::<StorageBytes>().await?)
+        })
+        .await?;
+
+    Ok(row.bytes)
+}
+
+/// Span count and last activity of a project, for project list pages
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectOverview {
+    #[serde(with = "clickhouse::serde::uuid")]
+    pub project_id: Uuid,
+    pub span_count: u64,
+    /// Latest span end time in nanoseconds
+    pub last_span_time: i64,
+}
+
+/// Overviews of many projects in one grouped query. Projects without spans have no
+/// entry.
+pub async fn get_project_overviews(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_ids: &[Uuid],
+) -> Result<Vec<ProjectOverview>> {
+    if project_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                project_id,
+                count() AS span_count,
+                toUnixTimestamp64Nano(MAX(end_time)) AS last_span_time
+            FROM {table}
+            WHERE {PROJECT_IDS_CONDITION}
+            GROUP BY project_id"
+        ))
+        .bind(project_ids);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<ProjectOverview>().await?)
+        })
+        .await
+}
+
+pub async fn get_span_by_id(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    span_id: Uuid,
+) -> Result<Option<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table} WHERE project_id = ? AND span_id = ? LIMIT 1"
+        ))
+        .bind(project_id)
+        .bind(span_id);
+
+    let mut span = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_optional::<CHSpan>().await?)
+        })
+        .await?;
+    if let Some(span) = span.as_mut() {
+        strip_span_sentinels(std::slice::from_mut(span), options);
+    }
+
+    Ok(span)
+}
+
+/// Like `get_span_by_id`, but reads with `FINAL` so that only the latest version of the
+/// span is returned even before ClickHouse has merged its parts. `FINAL` makes the read
+/// slower, so use it only where deduplication correctness matters.
+///
+/// Versions are only collapsed if the table is a `ReplacingMergeTree` over `updated_at`,
+/// as the spans table is since the `011000` migration:
+///
+/// ```sql
+/// CREATE TABLE spans
+/// (
+///     -- columns of `CHSpan`
+///     updated_at DateTime64(9, 'UTC')
+/// )
+/// ENGINE = ReplacingMergeTree(updated_at)
+/// ORDER BY (project_id, start_time, trace_id, span_id)
+/// ```
+///
+/// Rows are deduplicated by the whole sorting key, so a correction must keep the
+/// `start_time` of the version it replaces.
+pub async fn get_latest_span_by_id(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    span_id: Uuid,
+) -> Result<Option<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table} FINAL WHERE project_id = ? AND span_id = ? LIMIT 1"
+        ))
+        .bind(project_id)
+        .bind(span_id);
+
+    let mut span = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_optional::<CHSpan>().await?)
+        })
+        .await?;
+    if let Some(span) = span.as_mut() {
+        strip_span_sentinels(std::slice::from_mut(span), options);
+    }
+
+    Ok(span)
+}
+
+/// Returns the spans of a trace ordered by start time, capped at `MAX_SPANS_PER_TRACE`
+pub async fn get_spans_by_trace_id(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    trace_id: Uuid,
+) -> Result<Vec<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ? AND trace_id = ?
+            ORDER BY start_time ASC
+            LIMIT ?"
+        ))
+        .bind(project_id)
+        .bind(trace_id)
+        .bind(MAX_SPANS_PER_TRACE);
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    Ok(spans)
+}
+
+/// Spans of the user started within `[start_time, end_time]` ordered by start time, e.g.
+/// for a per-user activity view
+pub async fn get_spans_by_user(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    user_id: &str,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ?
+                AND user_id = ?
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)
+            ORDER BY start_time ASC
+            LIMIT ?"
+        ))
+        .bind(project_id)
+        .bind(user_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time))
+        .bind(limit);
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    Ok(spans)
+}
+
+#[derive(Row, Deserialize)]
+struct TraceIdRow {
+    #[serde(with = "clickhouse::serde::uuid")]
+    trace_id: Uuid,
+}
+
+/// Ids of the traces started within `[start_time, end_time]`, in the order they started.
+/// Lighter than fetching their spans, e.g. to process traces one by one downstream.
+pub async fn list_trace_ids(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<Uuid>> {
+    // Spans starting after the window can't start a trace within it
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT trace_id
+            FROM {table}
+            WHERE project_id = ? AND start_time <= fromUnixTimestamp64Nano(?)
+            GROUP BY trace_id
+            HAVING MIN(start_time) >= fromUnixTimestamp64Nano(?)
+            ORDER BY MIN(start_time) ASC, trace_id ASC
+            LIMIT ?"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(end_time))
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(limit);
+
+    let rows = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<TraceIdRow>().await?)
+        })
+        .await?;
+
+    Ok(rows.into_iter().map(|row| row.trace_id).collect())
+}
+
+/// A page of spans for incremental sync, see `get_spans_since`
+pub struct SpansSince {
+    pub spans: Vec<CHSpan>,
+    /// Latest `start_time` of `spans`, or the requested watermark if there are none, to
+    /// pass as `since` on the next call
+    pub watermark: DateTime<Utc>,
+}
+
+/// Spans of the project started after `since`, oldest first, for external systems that
+/// mirror spans by advancing a watermark.
+///
+/// The page has about `limit` spans, but always includes every span started at the
+/// latest start time of the page, even past `limit`. Otherwise spans sharing that start
+/// time would be skipped once the watermark moves past it, since the next call only
+/// returns spans started strictly after it. Spans inserted late with a start time before
+/// the watermark are not returned.
+pub async fn get_spans_since(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    since: DateTime<Utc>,
+    limit: u64,
+) -> Result<SpansSince> {
+    let since_nanos = chrono_to_nanoseconds(since);
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ?
+                AND start_time > fromUnixTimestamp64Nano(?)
+                AND start_time <= (
+                    SELECT max(start_time) FROM (
+                        SELECT start_time FROM {table}
+                        WHERE project_id = ?
+                            AND start_time > fromUnixTimestamp64Nano(?)
+                        ORDER BY start_time ASC
+                        LIMIT ?
+                    )
+                )
+            ORDER BY start_time ASC, span_id ASC"
+        ))
+        .bind(project_id)
+        .bind(since_nanos)
+        .bind(project_id)
+        .bind(since_nanos)
+        .bind(limit);
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    let watermark = spans
+        .iter()
+        .map(|span| span.start_time)
+        .max()
+        .map(nanoseconds_to_chrono)
+        .unwrap_or(since);
+
+    Ok(SpansSince { spans, watermark })
+}
+
+/// Totals over all spans of a session
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionSummary {
+    pub span_count: u64,
+    pub trace_count: u64,
+    pub total_tokens: i64,
+    pub total_cost: f64,
+    /// Earliest span start time in nanoseconds
+    pub start_time: i64,
+    /// Latest span end time in nanoseconds
+    pub end_time: i64,
+}
+
+/// Returns `None` if the session has no spans
+pub async fn get_session_summary(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    session_id: &str,
+) -> Result<Option<SessionSummary>> {
+    let total_cost = to_ch_rounded_cost("SUM(total_cost)", options.cost_precision());
+    // Grouping by session makes the query return no rows, rather than a row of zeros,
+    // for sessions without spans
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                count() AS span_count,
+                uniqExact(trace_id) AS trace_count,
+                SUM(total_tokens) AS total_tokens,
+                {total_cost} AS total_cost,
+                toUnixTimestamp64Nano(MIN(start_time)) AS start_time,
+                toUnixTimestamp64Nano(MAX(end_time)) AS end_time
+            FROM {table}
+            WHERE project_id = ? AND session_id = ?
+            GROUP BY session_id"
+        ))
+        .bind(project_id)
+        .bind(session_id);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_optional::<SessionSummary>().await?)
+        })
+        .await
+}
+
+#[derive(Row, Deserialize)]
+struct CostValue {
+    total_cost: f64,
+}
+
+/// Cost of the user's spans started within `[start, end]`, e.g. to enforce a spend cap.
+/// 0 if the user has no spans in the window.
+pub async fn get_user_cost_in_window(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    user_id: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<f64> {
+    let total_cost = to_ch_rounded_cost("toFloat64(SUM(total_cost))", options.cost_precision());
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                {total_cost} AS total_cost
+            FROM {table}
+            WHERE project_id = ?
+                AND user_id = ?
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)"
+        ))
+        .bind(project_id)
+        .bind(user_id)
+        .bind(chrono_to_nanoseconds(start))
+        .bind(chrono_to_nanoseconds(end));
+
+    let row = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_optional::<CostValue>().await?)
+        })
+        .await?;
+
+    Ok(row.map(|row| row.total_cost).unwrap_or(0.0))
+}
+
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserCost {
+    pub user_id: String,
+    pub total_cost: f64,
+}
+
+/// Users whose spans in `window` cost more than `budget`, most expensive first. Spans
+/// without a user id are left out. A relative window ends now, so future-dated spans
+/// don't count towards the budget.
+pub async fn user_ids_over_budget(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    budget: f64,
+    window: TimeRange,
+) -> Result<Vec<UserCost>> {
+    let total_cost = to_ch_rounded_cost("toFloat64(SUM(total_cost))", options.cost_precision());
+    let (window_start, window_end) = window.to_ch_bounds();
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                user_id,
+                {total_cost} AS total_cost
+            FROM {table}
+            WHERE project_id = ?
+                AND user_id != '{NULL_SENTINEL}'
+                AND start_time >= {window_start}
+                AND start_time <= {window_end}
+            GROUP BY user_id
+            HAVING total_cost > ?
+            ORDER BY total_cost DESC"
+        ))
+        .bind(project_id)
+        .bind(budget);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<UserCost>().await?)
+        })
+        .await
+}
+
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUsage {
+    pub user_id: String,
+    pub total_cost: f64,
+    pub total_tokens: i64,
+}
+
+/// Users ranked by the cost of their spans started within `[start_time, end_time]`, most
+/// expensive first, one page of `limit` users after `offset`. Spans without a user id are
+/// left out.
+pub async fn get_top_users_by_cost(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<UserUsage>> {
+    let total_cost = to_ch_rounded_cost("toFloat64(SUM(total_cost))", options.cost_precision());
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                user_id,
+                {total_cost} AS total_cost,
+                toInt64(SUM(total_tokens)) AS total_tokens
+            FROM {table}
+            WHERE project_id = ?
+                AND user_id != '{NULL_SENTINEL}'
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)
+            GROUP BY user_id
+            ORDER BY total_cost DESC, user_id ASC
+            LIMIT ? OFFSET ?"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time))
+        .bind(limit)
+        .bind(offset);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<UserUsage>().await?)
+        })
+        .await
+}
+
+/// Totals over all spans of a trace
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceSummary {
+    pub span_count: u64,
+    pub total_tokens: i64,
+    pub input_cost: f64,
+    pub output_cost: f64,
+    pub total_cost: f64,
+    /// Time between the earliest span start and the latest span end
+    pub latency_seconds: f64,
+    pub models: Vec<String>,
+    pub providers: Vec<String>,
+}
+
+/// Returns `None` if the trace has no spans
+pub async fn get_trace_summary(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    trace_id: Uuid,
+) -> Result<Option<TraceSummary>> {
+    let latency = NumericColumn::TraceLatencySeconds.to_ch_trace_expression();
+    let cost_precision = options.cost_precision();
+    let input_cost = to_ch_rounded_cost("SUM(input_cost)", cost_precision);
+    let output_cost = to_ch_rounded_cost("SUM(output_cost)", cost_precision);
+    let total_cost = to_ch_rounded_cost("SUM(total_cost)", cost_precision);
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                count() AS span_count,
+                SUM(total_tokens) AS total_tokens,
+                {input_cost} AS input_cost,
+                {output_cost} AS output_cost,
+                {total_cost} AS total_cost,
+                toFloat64({latency}) AS latency_seconds,
+                groupUniqArrayIf(model, model != '{NULL_SENTINEL}') AS models,
+                groupUniqArrayIf(provider, provider != '{NULL_SENTINEL}') AS providers
+            FROM {table}
+            WHERE project_id = ? AND trace_id = ?
+            GROUP BY trace_id"
+        ))
+        .bind(project_id)
+        .bind(trace_id);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_optional::<TraceSummary>().await?)
+        })
+        .await
+}
+
+#[derive(Row, Deserialize)]
+struct SpanTypeCount {
+    span_type: u8,
+    count: u64,
+}
+
+/// Number of spans of each type in the time range, most common first. The types can be
+/// turned into `db::trace::SpanType` with `SpanType::try_from`.
+pub async fn get_span_type_counts(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<(u8, u64)>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT span_type, count() AS count
+            FROM {table}
+            WHERE
+                project_id = ?
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)
+            GROUP BY span_type
+            ORDER BY count DESC"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time));
+
+    let rows = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<SpanTypeCount>().await?)
+        })
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.span_type, row.count))
+        .collect())
+}
+
+#[derive(Row, Deserialize)]
+struct TraceValue<T> {
+    #[serde(with = "clickhouse::serde::uuid")]
+    trace_id: Uuid,
+    value: T,
+}
+
+/// Traces with the highest cost among spans in the time range, most expensive first
+pub async fn get_most_expensive_traces(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<(Uuid, f64)>> {
+    get_top_traces(
+        clickhouse,
+        table,
+        options,
+        &to_ch_rounded_cost("SUM(total_cost)", options.cost_precision()),
+        project_id,
+        start_time,
+        end_time,
+        limit,
+    )
+    .await
+}
+
+/// Traces that used the most tokens among spans in the time range, largest first
+pub async fn get_most_token_heavy_traces(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<(Uuid, i64)>> {
+    get_top_traces(
+        clickhouse,
+        table,
+        options,
+        "SUM(total_tokens)",
+        project_id,
+        start_time,
+        end_time,
+        limit,
+    )
+    .await
+}
+
+/// Traces with the highest latency among spans in the time range, slowest first
+pub async fn get_slowest_traces(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<(Uuid, f64)>> {
+    let latency = format!(
+        "toFloat64({})",
+        NumericColumn::TraceLatencySeconds.to_ch_trace_expression()
+    );
+    let traces = get_top_traces(
+        clickhouse, table, options, &latency, project_id, start_time, end_time, limit,
+    )
+    .await?;
+
+    Ok(traces
+        .into_iter()
+        .map(|(trace_id, latency)| (trace_id, round_small_values_to_zero(latency)))
+        .collect())
+}
+
+async fn get_top_traces<T>(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    metric: &str,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<(Uuid, T)>>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT trace_id, {metric} AS value
+            FROM {table}
+            WHERE
+                project_id = ?
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)
+            GROUP BY trace_id
+            ORDER BY value DESC
+            LIMIT ?"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time))
+        .bind(limit);
+
+    let rows = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<TraceValue<T>>().await?)
+        })
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.trace_id, row.value))
+        .collect())
+}
+
+/// When a model was used in a project, for deprecation planning
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelWindow {
+    pub model: String,
+    /// Start time of the first span in nanoseconds
+    pub first_seen: i64,
+    /// Start time of the last span in nanoseconds
+    pub last_seen: i64,
+    pub span_count: u64,
+}
+
+/// First and last use of every model in the project, most recently used first
+pub async fn get_model_usage_windows(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+) -> Result<Vec<ModelWindow>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                model,
+                toUnixTimestamp64Nano(MIN(start_time)) AS first_seen,
+                toUnixTimestamp64Nano(MAX(start_time)) AS last_seen,
+                count() AS span_count
+            FROM {table}
+            WHERE project_id = ? AND model != '{NULL_SENTINEL}'
+            GROUP BY model
+            ORDER BY last_seen DESC"
+        ))
+        .bind(project_id);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<ModelWindow>().await?)
+        })
+        .await
+}
+
+/// How latency is attributed to models in `get_latency_summary_by_model`
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum ModelLatencyGrouping {
+    /// Latency of the whole trace, attributed to the model with the most spans in it.
+    /// Traces without any LLM spans are left out.
+    DominantModel,
+    /// Latency of every individual LLM span, attributed to its own model
+    PerSpan,
+}
+
+/// Latency statistics of a model in seconds
+#[derive(Row, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelLatencySummary {
+    pub model: String,
+    /// Number of spans or traces the statistics are computed over
+    pub sample_count: u64,
+    pub min_seconds: f64,
+    pub max_seconds: f64,
+    pub avg_seconds: f64,
+    pub p50_seconds: f64,
+    pub p95_seconds: f64,
+}
+
+/// Latency statistics of every model in one query, e.g. for a model comparison table
+pub async fn get_latency_summary_by_model(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    grouping: ModelLatencyGrouping,
+) -> Result<Vec<ModelLatencySummary>> {
+    let latencies = match grouping {
+        ModelLatencyGrouping::DominantModel => {
+            let latency = NumericColumn::TraceLatencySeconds.to_ch_trace_expression();
+            format!(
+                "SELECT
+                    top_models[1] AS model,
+                    value
+                FROM (
+                    SELECT
+                        MIN(start_time) AS time,
+                        topKIf(1)(model, model != '{NULL_SENTINEL}') AS top_models,
+                        toFloat64({latency}) AS value
+                    FROM {table}
+                    WHERE project_id = ?
+                    GROUP BY trace_id
+                )
+                WHERE
+                    notEmpty(top_models)
+                    AND time >= fromUnixTimestamp64Nano(?)
+                    AND time <= fromUnixTimestamp64Nano(?)"
+            )
+        }
+        ModelLatencyGrouping::PerSpan => format!(
+            "SELECT
+                model,
+                (toUnixTimestamp64Nano(end_time) - toUnixTimestamp64Nano(start_time)) / 1e9
+                    AS value
+            FROM {table}
+            WHERE
+                project_id = ?
+                AND model != '{NULL_SENTINEL}'
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)"
+        ),
+    };
+
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT
+                model,
+                count() AS sample_count,
+                min(value) AS min_seconds,
+                max(value) AS max_seconds,
+                avg(value) AS avg_seconds,
+                quantile(0.5)(value) AS p50_seconds,
+                quantile(0.95)(value) AS p95_seconds
+            FROM ({latencies})
+            GROUP BY model
+            ORDER BY model"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time));
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<ModelLatencySummary>().await?)
+        })
+        .await
+}
+
+/// Columns spans can be listed by. Only these map to SQL, so the sort column is never
+/// user-controlled text.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SpanSortColumn {
+    #[default]
+    StartTime,
+    TotalCost,
+    TotalTokens,
+    Name,
+}
+
+impl SpanSortColumn {
+    fn to_ch_column(&self) -> &'static str {
+        match self {
+            SpanSortColumn::StartTime => "start_time",
+            SpanSortColumn::TotalCost => "total_cost",
+            SpanSortColumn::TotalTokens => "total_tokens",
+            SpanSortColumn::Name => "name",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct SpanSort {
+    pub column: SpanSortColumn,
+    pub desc: bool,
+}
+
+impl Default for SpanSort {
+    /// Most recent first
+    fn default() -> Self {
+        Self {
+            column: SpanSortColumn::StartTime,
+            desc: true,
+        }
+    }
+}
+
+impl SpanSort {
+    /// Ties are broken by start time and span id, so that pages don't overlap
+    fn to_ch_order_by(&self) -> String {
+        let direction = if self.desc { "DESC" } else { "ASC" };
+        match self.column {
+            SpanSortColumn::StartTime => format!("start_time {direction}, span_id {direction}"),
+            column => format!(
+                "{} {direction}, start_time {direction}, span_id {direction}",
+                column.to_ch_column()
+            ),
+        }
+    }
+}
+
+/// Lists the spans started within `[start_time, end_time]`, one page of `limit` spans
+/// after `offset` in the order of `sort`
+pub async fn query_spans(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    sort: SpanSort,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ?
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)
+            ORDER BY {}
+            LIMIT ? OFFSET ?",
+            sort.to_ch_order_by()
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time))
+        .bind(limit)
+        .bind(offset);
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    Ok(spans)
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum SpanNameMatch {
+    Prefix,
+    Substring,
+}
+
+impl SpanNameMatch {
+    fn to_ch_condition(&self) -> &str {
+        match self {
+            SpanNameMatch::Prefix => "startsWith(name, ?)",
+            SpanNameMatch::Substring => "position(name, ?) > 0",
+        }
+    }
+}
+
+/// Finds spans whose name matches `query` within the time range, most recent first.
+///
+/// Matching is case-sensitive in both modes, because `startsWith` and `position`
+/// compare bytes as-is.
+pub async fn search_spans_by_name(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    query: &str,
+    name_match: SpanNameMatch,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<CHSpan>> {
+    let query_string = format!(
+        "SELECT ?fields FROM {table}
+        WHERE project_id = ?
+            AND {}
+            AND start_time >= fromUnixTimestamp64Nano(?)
+            AND start_time <= fromUnixTimestamp64Nano(?)
+        ORDER BY start_time DESC
+        LIMIT ?",
+        name_match.to_ch_condition()
+    );
+
+    let query = options
+        .apply(&clickhouse)
+        .query(&query_string)
+        .bind(project_id)
+        .bind(query)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time))
+        .bind(limit);
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    Ok(spans)
+}
+
+/// Spans that used tokens but have no cost, most recent first. These usually belong to
+/// models missing from the pricing table, see also `get_models_missing_cost`.
+pub async fn get_spans_missing_cost(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit: u64,
+) -> Result<Vec<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ?
+                AND total_tokens > 0
+                AND total_cost = 0
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)
+            ORDER BY start_time DESC
+            LIMIT ?"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time))
+        .bind(limit);
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    Ok(spans)
+}
+
+#[derive(Row, Deserialize)]
+struct ModelSpanCount {
+    model: String,
+    count: u64,
+}
+
+/// Models with spans that used tokens but have no cost, with the number of such spans,
+/// most affected first
+pub async fn get_models_missing_cost(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<(String, u64)>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT model, count() AS count
+            FROM {table}
+            WHERE project_id = ?
+                AND total_tokens > 0
+                AND total_cost = 0
+                AND start_time >= fromUnixTimestamp64Nano(?)
+                AND start_time <= fromUnixTimestamp64Nano(?)
+            GROUP BY model
+            ORDER BY count DESC"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time));
+
+    let rows = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<ModelSpanCount>().await?)
+        })
+        .await?;
+
+    Ok(rows.into_iter().map(|row| (row.model, row.count)).collect())
+}
+
+/// Spans whose `total_tokens` is not the sum of their prompt and completion tokens,
+/// most recent first
+pub async fn find_inconsistent_token_spans(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    limit: u64,
+) -> Result<Vec<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ?
+                AND total_tokens != prompt_tokens + completion_tokens
+            ORDER BY start_time DESC
+            LIMIT ?"
+        ))
+        .bind(project_id)
+        .bind(limit);
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    Ok(spans)
+}
+
+/// Sets `total_tokens` to the sum of prompt and completion tokens wherever they differ
+/// and returns the number of repaired spans.
+///
+/// The update is a mutation that rewrites the affected parts. It is waited for, so the
+/// spans are consistent once this returns, but it is expensive on large projects.
+pub async fn repair_token_totals(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    project_id: Uuid,
+) -> Result<u64> {
+    let inconsistent = clickhouse
+        .query(&format!(
+            "SELECT count() AS count FROM {table}
+            WHERE project_id = ? AND total_tokens != prompt_tokens + completion_tokens"
+        ))
+        .bind(project_id)
+        .fetch_one::<SpanCount>()
+        .await?;
+    if inconsistent.count == 0 {
+        return Ok(0);
+    }
+
+    clickhouse
+        .query(&format!(
+            "ALTER TABLE {table}
+            UPDATE total_tokens = prompt_tokens + completion_tokens
+            WHERE project_id = ? AND total_tokens != prompt_tokens + completion_tokens
+            SETTINGS mutations_sync = 2"
+        ))
+        .bind(project_id)
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to repair token totals: {:?}", e))?;
+
+    Ok(inconsistent.count)
+}
+
+/// Spans of the project starting more than `tolerance` after now, most future first
+pub async fn find_future_spans(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    tolerance: chrono::Duration,
+) -> Result<Vec<CHSpan>> {
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ? AND start_time > fromUnixTimestamp64Nano(?)
+            ORDER BY start_time DESC"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(Utc::now() + tolerance));
+
+    let mut spans = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<CHSpan>().await?)
+        })
+        .await?;
+    strip_span_sentinels(&mut spans, options);
+
+    Ok(spans)
+}
+
+/// Moves spans starting more than `tolerance` after now to start now, keeping their
+/// durations, and returns the number of moved spans.
+///
+/// `start_time` is part of the sorting key, so it can't be updated in place. The moved
+/// copies are inserted first and the originals deleted after, so the spans briefly
+/// appear twice rather than not at all. Only the rows that were read are deleted, so
+/// future spans arriving in between are left for the next run. Fails if `tolerance` is
+/// negative, as the copies would then be in the future themselves.
+pub async fn clamp_future_spans(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    project_id: Uuid,
+    tolerance: chrono::Duration,
+) -> Result<u64> {
+    if tolerance < chrono::Duration::zero() {
+        return Err(anyhow::anyhow!(
+            "Tolerance must not be negative, got {}",
+            tolerance
+        ));
+    }
+
+    let now = Utc::now();
+    let max_start_time = chrono_to_nanoseconds(now + tolerance);
+    // Read as stored, so that the copies keep their sentinels
+    let spans = clickhouse
+        .query(&format!(
+            "SELECT ?fields FROM {table}
+            WHERE project_id = ? AND start_time > fromUnixTimestamp64Nano(?)"
+        ))
+        .bind(project_id)
+        .bind(max_start_time)
+        .fetch_all::<CHSpan>()
+        .await?;
+    if spans.is_empty() {
+        return Ok(0);
+    }
+
+    let now = chrono_to_nanoseconds(now);
+    let clamped = spans
+        .iter()
+        .map(|span| CHSpan {
+            start_time: now,
+            end_time: now + (span.end_time - span.start_time).max(0),
+            updated_at: now,
+            ..span.clone()
+        })
+        .collect::<Vec<CHSpan>>();
+    insert_spans(clickhouse.clone(), table, &clamped).await?;
+
+    let span_ids = spans.iter().map(|span| span.span_id).collect::<Vec<Uuid>>();
+    let start_times = spans
+        .iter()
+        .map(|span| span.start_time)
+        .collect::<Vec<i64>>();
+    clickhouse
+        .query(&format!(
+            "DELETE FROM {table}
+            WHERE project_id = ?
+                AND span_id IN ?
+                AND toUnixTimestamp64Nano(start_time) IN ?"
+        ))
+        .bind(project_id)
+        .bind(span_ids)
+        .bind(start_times)
+        .execute()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to delete future spans: {:?}", e))?;
+
+    Ok(spans.len() as u64)
+}
+
+/// Traces with fewer than `min_spans` spans are not counted, e.g. to leave out trivial
+/// traces of health checks. `min_spans` of 1 counts all traces.
+pub async fn get_total_trace_count_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    min_spans: u64,
+    count_mode: CountMode,
+) -> Result<Vec<IntMetricTimeValue>> {
+    // The dashboard template counts exactly
+    if min_spans <= 1
+        && count_mode == CountMode::Exact
+        && table.as_str() == SpanTable::default().as_str()
+    {
+        return execute_dashboard_query(
+            &clickhouse,
+            options,
+            DashboardMetric::TraceCount,
+            Aggregation::Total,
+            group_by_interval,
+            project_id,
+            &time_range,
+        )
+        .await;
+    }
+
+    let query_string = build_trace_count_metric_query(
+        table,
+        group_by_interval,
+        project_id,
+        &time_range,
+        min_spans,
+        count_mode,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Final SQL of the trace count metric
+pub fn build_trace_count_metric_query(
+    table: &SpanTable,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: &TimeRange,
+    min_spans: u64,
+    count_mode: CountMode,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let ch_count = count_mode.to_ch_count_distinct("trace_id");
+    let having_statement = if min_spans > 1 {
+        format!("HAVING count() >= {min_spans}")
+    } else {
+        String::new()
+    };
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+
+    format!(
+        "
+    WITH traces AS (
+        SELECT
+            trace_id,
+            project_id,
+            {ch_round_time}(MIN(start_time)) as time
+        FROM {table}
+        GROUP BY project_id, trace_id
+        {having_statement}
+    )
+    SELECT
+        time,
+        toInt64({ch_count}) as value
+    FROM traces
+    WHERE
+        project_id = '{project_id}'
+        AND {time_condition}
+    {group_by_time_statement}"
+    )
+}
+
+/// Numeric per-trace values that can be aggregated into a metric
+#[derive(Clone, Copy)]
+pub enum NumericColumn {
+    TotalTokens,
+    /// Input tokens only
+    PromptTokens,
+    /// Generated tokens only
+    CompletionTokens,
+    TotalCost,
+    TraceLatencySeconds,
+    /// Maximum span depth within the trace
+    TraceDepth,
+}
+
+/// Latency of a single span, in seconds
+const SPAN_LATENCY_SECONDS: &str =
+    "(toUnixTimestamp64Nano(end_time) - toUnixTimestamp64Nano(start_time)) / 1e9";
+
+impl NumericColumn {
+    /// Expression computed over the spans of a single trace
+    pub(super) fn to_ch_trace_expression(&self) -> &'static str {
+        match self {
+            NumericColumn::TotalTokens => "SUM(total_tokens)",
+            NumericColumn::PromptTokens => "SUM(prompt_tokens)",
+            NumericColumn::CompletionTokens => "SUM(completion_tokens)",
+            NumericColumn::TotalCost => "SUM(total_cost)",
+            NumericColumn::TraceLatencySeconds => {
+                "(toUnixTimestamp64Nano(MAX(end_time)) - toUnixTimestamp64Nano(MIN(start_time))) / 1e9"
+            }
+            NumericColumn::TraceDepth => "MAX(depth)",
+        }
+    }
+}
+
+/// Metrics computed directly over the spans of each bucket, without grouping by trace
+#[derive(Clone, Copy)]
+pub enum SpanLevelMetric {
+    DistinctSpanNames,
+    CostPer1kTokens,
+    IoCostRatio,
+    NonLlmSpanCount,
+    LlmSpanCount,
+    UnpricedSpanCount,
+    IngestionLagSeconds,
+    SpanCount,
+    TokenWeightedLatencySeconds,
+}
+
+impl SpanLevelMetric {
+    fn to_ch_expression(&self) -> &'static str {
+        match self {
+            SpanLevelMetric::DistinctSpanNames => "toInt64(uniqExact(name))",
+            SpanLevelMetric::CostPer1kTokens => {
+                "ifNull(SUM(total_cost) / nullIf(SUM(total_tokens), 0) * 1000, 0)"
+            }
+            SpanLevelMetric::IoCostRatio => {
+                "ifNull(SUM(output_cost) / nullIf(SUM(input_cost), 0), 0)"
+            }
+            SpanLevelMetric::NonLlmSpanCount => "toInt64(countIf(total_tokens = 0))",
+            SpanLevelMetric::LlmSpanCount => "toInt64(countIf(total_tokens > 0))",
+            SpanLevelMetric::UnpricedSpanCount => "toInt64(countIf(NOT cost_known))",
+            SpanLevelMetric::SpanCount => "toInt64(count())",
+            SpanLevelMetric::TokenWeightedLatencySeconds => {
+                "ifNull(
+            SUM((toUnixTimestamp64Nano(end_time) - toUnixTimestamp64Nano(start_time)) / 1e9
+                * total_tokens)
+                / nullIf(SUM(total_tokens), 0),
+            0
+        )"
+            }
+            // Spans written before `inserted_at` existed have it at the epoch
+            SpanLevelMetric::IngestionLagSeconds => {
+                "if(
+            countIf(inserted_at > toDateTime64(0, 9, 'UTC')) = 0,
+            0,
+            avgIf(
+                (toUnixTimestamp64Nano(inserted_at) - toUnixTimestamp64Nano(end_time)) / 1e9,
+                inserted_at > toDateTime64(0, 9, 'UTC')
+            )
+        )"
+            }
+        }
+    }
+}
+
+/// Number of distinct span names per bucket. A sudden jump usually means that unique
+/// values (e.g. ids) ended up in span names.
+pub async fn get_distinct_span_names_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<IntMetricTimeValue>> {
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::DistinctSpanNames,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Cost per span name and bucket. Only the `limit_top_n` most expensive names in the
+/// time range get their own series, the rest are rolled up into `"other"`.
+pub async fn get_cost_metrics_by_name(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    limit_top_n: u64,
+    fx_rate: Option<FxRate>,
+) -> Result<Vec<NameMetricTimeValue>> {
+    let query_string = build_cost_by_name_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        limit_top_n,
+        fx_rate,
+        options.cost_precision(),
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Cost per session and bucket, for customers that bill by conversation. Spans without
+/// a session are left out. Only the `limit_top_n` most expensive sessions in the time
+/// range get their own series, the rest are rolled up into `"other"`.
+pub async fn get_cost_metrics_by_session(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    limit_top_n: u64,
+    fx_rate: Option<FxRate>,
+) -> Result<Vec<SessionMetricTimeValue>> {
+    let query_string = build_cost_by_session_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        limit_top_n,
+        fx_rate,
+        options.cost_precision(),
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Cost per provider and bucket, with the same top-N rollup as the other cost
+/// breakdowns. Spans without a provider are left out. Use `to_percent_of_total` for the
+/// share of spend of each provider.
+pub async fn get_cost_metrics_by_provider(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    limit_top_n: u64,
+    fx_rate: Option<FxRate>,
+) -> Result<Vec<ProviderMetricTimeValue>> {
+    let query_string = build_cost_by_provider_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        limit_top_n,
+        fx_rate,
+        options.cost_precision(),
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Metric per value of the span attribute `key`, which must be extracted into the
+/// `attributes` column by an `AttributeExtractionConfig`. Spans without the attribute
+/// are left out.
+pub async fn get_metrics_by_attribute(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    metric_kind: MetricKind,
+    key: &str,
+) -> Result<Vec<NameMetricTimeValue>> {
+    require_column(
+        &clickhouse,
+        table.as_str(),
+        "attributes",
+        "007000-span-attributes.sql",
+    )
+    .await?;
+    let query_string = build_metric_by_attribute_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        metric_kind,
+        options.cost_precision(),
+    );
+    let query = options
+        .apply(&clickhouse)
+        .query(&query_string)
+        .bind(key)
+        .bind(key);
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<NameMetricTimeValue>().await?)
+        })
+        .await
+}
+
+/// The attribute key is left as two `?` placeholders
+pub fn build_metric_by_attribute_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    metric_kind: MetricKind,
+    cost_precision: u32,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let time_condition = time_range.to_ch_condition("start_time");
+    let with_fill_statement = time_range.to_ch_with_fill(group_by_interval);
+    let metric = match metric_kind {
+        MetricKind::TraceCount => "toFloat64(uniqExact(trace_id))".to_string(),
+        MetricKind::SpanCount => "toFloat64(count())".to_string(),
+        MetricKind::TotalTokens => "toFloat64(SUM(total_tokens))".to_string(),
+        MetricKind::TotalCost => to_ch_rounded_cost("SUM(total_cost)", cost_precision),
+    };
+
+    format!(
+        "
+    SELECT
+        {ch_round_time}(start_time) AS time,
+        attributes[?] AS name,
+        {metric} AS value
+    FROM {table}
+    WHERE
+        project_id = '{project_id}'
+        AND {time_condition}
+        AND mapContains(attributes, ?)
+    GROUP BY time, name
+    ORDER BY name, time
+    {with_fill_statement}"
+    )
+}
+
+/// Number of spans per `ingest_source` and bucket, e.g. to confirm that the spans of a
+/// new exporter version are landing. Spans written before the column existed are
+/// counted under NULL_SENTINEL.
+pub async fn get_span_count_by_source(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<NameMetricTimeValue>> {
+    require_column(
+        &clickhouse,
+        table.as_str(),
+        "ingest_source",
+        "009000-span-ingest-source.sql",
+    )
+    .await?;
+    let query_string =
+        build_span_count_by_source_query(table, project_id, group_by_interval, &time_range);
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+pub fn build_span_count_by_source_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let time_condition = time_range.to_ch_condition("start_time");
+    let with_fill_statement = time_range.to_ch_with_fill(group_by_interval);
+
+    format!(
+        "
+    SELECT
+        {ch_round_time}(start_time) AS time,
+        ingest_source AS name,
+        toFloat64(count()) AS value
+    FROM {table}
+    WHERE
+        project_id = '{project_id}'
+        AND {time_condition}
+    GROUP BY time, name
+    ORDER BY name, time
+    {with_fill_statement}"
+    )
+}
+
+/// Traces per bucket split by the number of distinct models their spans used, as the
+/// series `"single_model"` and `"multi_model"`, e.g. to see how often routing or
+/// fallbacks kick in. Traces without any model are left out.
+pub async fn get_trace_count_by_model_diversity(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<NameMetricTimeValue>> {
+    let query_string = build_trace_count_by_model_diversity_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+pub fn build_trace_count_by_model_diversity_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> String {
+    let traces_cte = per_trace_cte(
+        table,
+        group_by_interval,
+        &format!("uniqExactIf(model, model != '{NULL_SENTINEL}')"),
+        &[format!("project_id = '{project_id}'")],
+    );
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+
+    // Both series are filled before they are split into rows, so that each covers the
+    // whole time range even if it has no traces
+    format!(
+        "
+    WITH {traces_cte}
+    SELECT
+        time,
+        name,
+        value
+    FROM (
+        SELECT
+            time,
+            toFloat64(countIf(value = 1)) AS single_model,
+            toFloat64(countIf(value > 1)) AS multi_model
+        FROM traces
+        WHERE {time_condition}
+        {group_by_time_statement}
+    )
+    ARRAY JOIN
+        ['single_model', 'multi_model'] AS name,
+        [single_model, multi_model] AS value
+    ORDER BY name, time"
+    )
+}
+
+/// Token usage and cost per model and bucket, with every model filled over the whole
+/// time range. Spans without a model are left out.
+pub async fn get_model_usage_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    include_io_cost: bool,
+    fx_rate: Option<FxRate>,
+) -> Result<Vec<ModelMetricTimeValue>> {
+    let query_string = build_model_usage_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        include_io_cost,
+        fx_rate,
+        options.cost_precision(),
+    );
+
+    let mut values: Vec<ModelMetricTimeValue> =
+        execute_query(&clickhouse, options, &query_string).await?;
+    if include_io_cost {
+        // Rows added by WITH FILL have NULL costs
+        for value in values.iter_mut() {
+            value.input_cost.get_or_insert(0.0);
+            value.output_cost.get_or_insert(0.0);
+        }
+    }
+
+    Ok(values)
+}
+
+/// Latency of the spans of each model, aggregated per bucket. Only the `limit_top_n`
+/// models with the most spans in the time range get their own series, the rest are
+/// rolled up into `"other"`. Spans without a model are left out.
+pub async fn get_latency_metrics_by_model(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+    limit_top_n: u64,
+    negative_latency: NegativeLatencyPolicy,
+) -> Result<Vec<ModelFloatMetricTimeValue>> {
+    let query_string = build_latency_by_model_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        aggregation,
+        limit_top_n,
+        negative_latency,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+pub fn build_latency_by_model_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    aggregation: Aggregation,
+    limit_top_n: u64,
+    negative_latency: NegativeLatencyPolicy,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let ch_aggregation = aggregation.to_ch_agg_function();
+    let time_condition = time_range.to_ch_condition("start_time");
+    let with_fill_statement = time_range.to_ch_with_fill(group_by_interval);
+    let latency = negative_latency.to_ch_latency(SPAN_LATENCY_SECONDS);
+
+    format!(
+        "
+    WITH top_models AS (
+        SELECT model
+        FROM {table}
+        WHERE
+            project_id = '{project_id}'
+            AND {time_condition}
+            AND model != '{NULL_SENTINEL}'
+        GROUP BY model
+        ORDER BY count() DESC
+        LIMIT {limit_top_n}
+    )
+    SELECT
+        time,
+        series_name AS model,
+        value
+    FROM (
+        SELECT
+            {ch_round_time}(start_time) AS time,
+            if(model IN (SELECT model FROM top_models), model, 'other') AS series_name,
+            toFloat64(ifNull({ch_aggregation}({latency}), 0)) AS value
+        FROM {table}
+        WHERE
+            project_id = '{project_id}'
+            AND {time_condition}
+            AND model != '{NULL_SENTINEL}'
+        GROUP BY time, series_name
+    )
+    ORDER BY model, time
+    {with_fill_statement}"
+    )
+}
+
+/// How latency is attributed to providers, since a trace may call several of them
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum ProviderLatencyGrouping {
+    /// Latency of the whole trace, attributed to the provider with the most spans in it.
+    /// Traces without any LLM spans are left out.
+    DominantProvider,
+    /// Latency of every individual LLM span, attributed to its own provider
+    PerSpan,
+}
+
+/// Latency per provider and bucket, aggregated with `aggregation`
+pub async fn get_latency_metrics_by_provider(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+    grouping: ProviderLatencyGrouping,
+) -> Result<Vec<ProviderMetricTimeValue>> {
+    let query_string = build_latency_by_provider_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        aggregation,
+        grouping,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Totals that can be compared between periods or broken down by span attribute
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum MetricKind {
+    TraceCount,
+    SpanCount,
+    TotalTokens,
+    TotalCost,
+}
+
+impl MetricKind {
+    fn to_ch_trace_expression(&self) -> &'static str {
+        match self {
+            MetricKind::TraceCount => "1",
+            MetricKind::SpanCount => "count()",
+            MetricKind::TotalTokens => "SUM(total_tokens)",
+            MetricKind::TotalCost => "SUM(total_cost)",
+        }
+    }
+}
+
+#[derive(Row, Deserialize)]
+struct PeriodComparison {
+    current: f64,
+    previous: f64,
+}
+
+/// Returns the total of `metric_kind` over `current_range` and its percent change
+/// against the preceding period of the same length, e.g. `(1234.0, 12.0)` for
+/// "1,234 traces (+12%)". The change is 0 if the previous period is empty.
+///
+/// As with the other per-trace metrics, a trace belongs to the period it started in.
+pub async fn get_metric_with_comparison(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    metric_kind: MetricKind,
+    project_id: Uuid,
+    current_range: TimeRange,
+) -> Result<(f64, f64)> {
+    let metric = metric_kind.to_ch_trace_expression();
+    let (start, end) = current_range.to_ch_bounds();
+    let previous_start = format!(
+        "{start} - INTERVAL {} SECOND",
+        current_range.duration_seconds()
+    );
+
+    let query_string = format!(
+        "
+    WITH traces AS (
+    SELECT
+        trace_id,
+        MIN(start_time) as time,
+        {metric} as value
+    FROM {table}
+    WHERE
+        project_id = '{project_id}'
+        AND start_time >= {previous_start}
+    GROUP BY trace_id
+    )
+    SELECT
+        toFloat64(sumIf(value, time >= {start} AND time <= {end})) AS current,
+        toFloat64(sumIf(value, time >= {previous_start} AND time < {start})) AS previous
+    FROM traces"
+    );
+
+    let rows: Vec<PeriodComparison> = execute_query(&clickhouse, options, &query_string).await?;
+    let Some(row) = rows.first() else {
+        return Ok((0.0, 0.0));
+    };
+    let change = if row.previous == 0.0 {
+        0.0
+    } else {
+        (row.current - row.previous) / row.previous * 100.0
+    };
+
+    Ok((row.current, change))
+}
+
+/// Total of a metric within one hour of the week
+#[derive(Row, Serialize, Deserialize, Debug)]
+pub struct HeatmapCell {
+    /// Day of the week in UTC, 1 for Monday to 7 for Sunday
+    pub dow: u8,
+    /// Hour of the day in UTC, 0 to 23
+    pub hour: u8,
+    pub value: f64,
+}
+
+/// Totals of `metric_kind` per day of the week and hour of the day over the traces started
+/// within `[start_time, end_time]`, e.g. to find the busiest hours for capacity planning.
+/// Only hours with traces have a cell.
+pub async fn get_usage_heatmap(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    metric_kind: MetricKind,
+) -> Result<Vec<HeatmapCell>> {
+    let metric = metric_kind.to_ch_trace_expression();
+    let value = match metric_kind {
+        MetricKind::TotalCost => to_ch_rounded_cost("SUM(value)", options.cost_precision()),
+        _ => "SUM(value)".to_string(),
+    };
+    let query = options
+        .apply(&clickhouse)
+        .query(&format!(
+            "
+    WITH traces AS (
+    SELECT
+        trace_id,
+        MIN(start_time) as time,
+        {metric} as value
+    FROM {table}
+    WHERE project_id = ?
+    GROUP BY trace_id
+    )
+    SELECT
+        toUInt8(toDayOfWeek(time)) AS dow,
+        toUInt8(toHour(time)) AS hour,
+        toFloat64({value}) AS value
+    FROM traces
+    WHERE
+        time >= fromUnixTimestamp64Nano(?)
+        AND time <= fromUnixTimestamp64Nano(?)
+    GROUP BY dow, hour
+    ORDER BY dow, hour"
+        ))
+        .bind(project_id)
+        .bind(chrono_to_nanoseconds(start_time))
+        .bind(chrono_to_nanoseconds(end_time));
+
+    options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<HeatmapCell>().await?)
+        })
+        .await
+}
+
+/// Time within each trace not covered by any span, in seconds, aggregated per bucket.
+/// High values point at agents stalling between tool calls.
+pub async fn get_span_gap_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let query_string = build_span_gap_metric_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        aggregation,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// The same metric over two time ranges, e.g. two deploy windows, for overlaying them
+/// on one chart. `time` in both series is the offset in seconds from the start of the
+/// respective range rather than a timestamp, so the series line up.
+pub async fn get_metric_overlay(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    metric_kind: MetricKind,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    range_a: TimeRange,
+    range_b: TimeRange,
+) -> Result<(Vec<FloatMetricTimeValue>, Vec<FloatMetricTimeValue>)> {
+    let query_a = build_rebased_metric_query(table, metric_kind, group_by_interval, &range_a);
+    let query_b = build_rebased_metric_query(table, metric_kind, group_by_interval, &range_b);
+
+    let options_b = options.for_concurrent_query();
+
+    let (values_a, values_b) = futures::try_join!(
+        execute_project_query(&clickhouse, options, &query_a, &[project_id]),
+        execute_project_query(&clickhouse, &options_b, &query_b, &[project_id]),
+    )?;
+
+    Ok((values_a, values_b))
+}
+
+/// Final SQL of a metric with `time` as the offset from the start of the time range. The
+/// project ids are left as a placeholder, see `span_metric_query`.
+pub fn build_rebased_metric_query(
+    table: &SpanTable,
+    metric_kind: MetricKind,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let metric = format!("toFloat64({})", metric_kind.to_ch_trace_expression());
+    let (start, _) = time_range.to_ch_bounds();
+    let query_string = span_metric_query(
+        table,
+        group_by_interval,
+        time_range,
+        Aggregation::Total,
+        &metric,
+        &[],
+        false,
+    );
+
+    format!(
+        "
+    SELECT
+        toUInt32(greatest(dateDiff('second', {ch_round_time}({start}), time), 0)) AS time,
+        value
+    FROM ({query_string})
+    ORDER BY time"
+    )
+}
+
+#[derive(Row, Deserialize)]
+struct LatencyHistogramBucket {
+    bucket_index: u64,
+    height: u64,
+}
+
+/// Trace latency histogram. `bucket_bounds` are the ascending lower bounds of the buckets
+/// in seconds; each bucket spans up to the next bound, and the last one is unbounded.
+/// Traces faster than the first bound are not counted.
+///
+/// Returns `(lower_bound, trace_count)` for every bucket, including empty ones.
+pub async fn get_latency_histogram(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    bucket_bounds: &[f64],
+) -> Result<Vec<(f64, u64)>> {
+    if bucket_bounds.is_empty() {
+        return Err(anyhow::anyhow!("At least one bucket bound is required"));
+    }
+    if bucket_bounds.iter().any(|bound| !bound.is_finite())
+        || bucket_bounds.windows(2).any(|pair| pair[0] >= pair[1])
+    {
+        return Err(anyhow::anyhow!(
+            "Bucket bounds must be finite and strictly ascending"
+        ));
+    }
+
+    let latency = NumericColumn::TraceLatencySeconds.to_ch_trace_expression();
+    let time_condition = TimeRange::Absolute {
+        start: start_time,
+        end: end_time,
+    }
+    .to_ch_condition("time");
+    // {:?} renders floats like 1.0 as 1.0 instead of 1
+    let ch_bounds = bucket_bounds
+        .iter()
+        .map(|bound| format!("{:?}", bound))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let query_string = format!(
+        "
+    WITH traces AS (
+    SELECT
+        trace_id,
+        project_id,
+        MIN(start_time) as time,
+        {latency} as value
+    FROM {table}
+    GROUP BY project_id, trace_id
+    )
+    SELECT
+        toUInt64(arrayCount(bound -> bound <= value, [{ch_bounds}])) AS bucket_index,
+        count() AS height
+    FROM traces
+    WHERE
+        project_id = '{project_id}'
+        AND {time_condition}
+    GROUP BY bucket_index"
+    );
+
+    let rows: Vec<LatencyHistogramBucket> =
+        execute_query(&clickhouse, options, &query_string).await?;
+
+    // Bucket index 0 holds the traces below the first bound, index i holds bucket i - 1
+    let mut heights = vec![0; bucket_bounds.len()];
+    for row in rows {
+        if row.bucket_index > 0 {
+            heights[row.bucket_index as usize - 1] = row.height;
+        }
+    }
+
+    Ok(bucket_bounds.iter().cloned().zip(heights).collect())
+}
+
+/// Dollars spent per thousand tokens in each bucket. Buckets without tokens are 0.
+pub async fn get_cost_per_1k_tokens_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::CostPer1kTokens,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Ratio of output to input cost in each bucket. Buckets without input cost are 0.
+pub async fn get_io_cost_ratio_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::IoCostRatio,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Number of spans without any tokens (tool calls, orchestration, etc.) per bucket
+pub async fn get_non_llm_span_count_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<IntMetricTimeValue>> {
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::NonLlmSpanCount,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Number of spans that used tokens, i.e. LLM calls, per bucket
+pub async fn get_llm_span_count_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<IntMetricTimeValue>> {
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::LlmSpanCount,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Number of spans per bucket whose model had no pricing, to catch gaps in the pricing
+/// table before they skew the cost metrics
+pub async fn get_unpriced_span_count_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<IntMetricTimeValue>> {
+    require_column(
+        &clickhouse,
+        table.as_str(),
+        "cost_known",
+        "005000-span-cost-known.sql",
+    )
+    .await?;
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::UnpricedSpanCount,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Average delay per bucket between a span ending and it being written to ClickHouse.
+/// A growing lag means that the ingestion pipeline is backing up.
+pub async fn get_ingestion_lag_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    require_column(
+        &clickhouse,
+        table.as_str(),
+        "inserted_at",
+        "008000-span-inserted-at.sql",
+    )
+    .await?;
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::IngestionLagSeconds,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Estimated savings from prompt caching per bucket, in USD.
+///
+/// Input costs are priced at the full input price, so each span's price per prompt token
+/// is `input_cost / prompt_tokens`. `cached_discount` is the share of that price a cached
+/// token saves, from 0 to 1, e.g. 0.5 for OpenAI or 0.9 for Anthropic cache reads. Spans
+/// without `cached_tokens` are left out.
+pub async fn get_cache_savings_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    cached_discount: f64,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    if !(0.0..=1.0).contains(&cached_discount) {
+        return Err(anyhow::anyhow!(
+            "Cached token discount must be between 0 and 1, got {}",
+            cached_discount
+        ));
+    }
+    require_column(
+        &clickhouse,
+        table.as_str(),
+        "cached_tokens",
+        "010000-span-cached-tokens.sql",
+    )
+    .await?;
+    let query_string = build_cache_savings_metric_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        cached_discount,
+        options.cost_precision(),
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+pub fn build_cache_savings_metric_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    cached_discount: f64,
+    cost_precision: u32,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let time_condition = time_range.to_ch_condition("start_time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+    let savings = to_ch_rounded_cost(
+        &format!(
+            "ifNull(
+            SUM(cached_tokens * input_cost / nullIf(prompt_tokens, 0)) * {cached_discount},
+            0
+        )"
+        ),
+        cost_precision,
+    );
+
+    format!(
+        "
+    SELECT
+        {ch_round_time}(start_time) AS time,
+        toFloat64({savings}) AS value
+    FROM {table}
+    WHERE
+        project_id = '{project_id}'
+        AND {time_condition}
+        AND cached_tokens IS NOT NULL
+    {group_by_time_statement}"
+    )
+}
+
+/// Latency of individual spans, in seconds, aggregated per bucket, e.g. to profile one
+/// operation with `span_name`. Unlike the trace latency metrics, spans are not grouped
+/// by trace.
+pub async fn get_span_latency_seconds_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+    span_name: Option<&str>,
+    negative_latency: NegativeLatencyPolicy,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let query_string = build_span_latency_metric_query(
+        table,
+        project_id,
+        group_by_interval,
+        &time_range,
+        aggregation,
+        span_name.is_some(),
+        negative_latency,
+    );
+    let mut query = options.apply(&clickhouse).query(&query_string);
+    if let Some(span_name) = span_name {
+        query = query.bind(span_name);
+    }
+
+    let mut values = options
+        .run(&clickhouse, async {
+            Ok(query.fetch_all::<FloatMetricTimeValue>().await?)
+        })
+        .await?;
+    for value in values.iter_mut() {
+        value.value = round_small_values_to_zero(value.value);
+    }
+
+    Ok(values)
+}
+
+/// With `filter_by_name`, the span name is left as a `?` placeholder
+pub fn build_span_latency_metric_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    aggregation: Aggregation,
+    filter_by_name: bool,
+    negative_latency: NegativeLatencyPolicy,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let ch_aggregation = aggregation.to_ch_agg_function();
+    let time_condition = time_range.to_ch_condition("start_time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+    let name_condition = if filter_by_name { "AND name = ?" } else { "" };
+    let latency = negative_latency.to_ch_latency(SPAN_LATENCY_SECONDS);
+
+    format!(
+        "
+    SELECT
+        {ch_round_time}(start_time) AS time,
+        toFloat64(ifNull({ch_aggregation}({latency}), 0)) AS value
+    FROM {table}
+    WHERE
+        project_id = '{project_id}'
+        AND {time_condition}
+        {name_condition}
+    {group_by_time_statement}"
+    )
+}
+
+/// Span latency per bucket averaged with the span's total tokens as weights, so that a
+/// 10000 token call counts more than a 10 token one. Buckets without tokens are 0.
+pub async fn get_token_weighted_latency_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::TokenWeightedLatencySeconds,
+        group_by_interval,
+        project_id,
+        &time_range,
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+/// Time ranges within `[start, end]` of at least `min_gap` without any span, a sign of
+/// dropped data when verifying a backfill, in chronological order.
+///
+/// Span counts are bucketed by minute, hour or day, whichever is finer than `min_gap`, so
+/// gap bounds are exact to a bucket.
+pub async fn find_ingestion_gaps(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    project_id: Uuid,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    min_gap: chrono::Duration,
+) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>> {
+    let group_by_interval = if min_gap < chrono::Duration::hours(1) {
+        GroupByInterval::Minute
+    } else if min_gap < chrono::Duration::days(1) {
+        GroupByInterval::Hour
+    } else {
+        GroupByInterval::Day
+    };
+    let query_string = build_span_level_metric_query(
+        table,
+        SpanLevelMetric::SpanCount,
+        group_by_interval,
+        project_id,
+        &TimeRange::Absolute { start, end },
+    );
+    let counts: Vec<IntMetricTimeValue> =
+        execute_query(&clickhouse, options, &query_string).await?;
+
+    Ok(empty_bucket_ranges(&counts, start, end, min_gap))
+}
+
+/// Runs of zero-count buckets of at least `min_gap`, clamped to `[start, end]`. The
+/// buckets are those of a zero-filled metric, so every bucket is present.
+fn empty_bucket_ranges(
+    counts: &[IntMetricTimeValue],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    min_gap: chrono::Duration,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let bucket_start = |count: &IntMetricTimeValue| {
+        DateTime::from_timestamp(count.time as i64, 0)
+            .unwrap_or(start)
+            .clamp(start, end)
+    };
+
+    let mut gaps = Vec::new();
+    let mut gap_start = None;
+    for count in counts {
+        match (count.value, gap_start) {
+            (0, None) => gap_start = Some(bucket_start(count)),
+            (value, Some(from)) if value > 0 => {
+                gaps.push((from, bucket_start(count)));
+                gap_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = gap_start {
+        gaps.push((from, end));
+    }
+
+    gaps.retain(|(from, to)| *to - *from >= min_gap);
+    gaps
+}
+
+/// Traces that end before they start, e.g. because of clock skew, are handled according
+/// to `negative_latency`
+pub async fn get_trace_latency_seconds_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+    negative_latency: NegativeLatencyPolicy,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    if negative_latency == NegativeLatencyPolicy::default() {
+        // Can use the cached dashboard templates
+        return get_numeric_metric(
+            clickhouse,
+            table,
+            options,
+            NumericColumn::TraceLatencySeconds,
+            aggregation,
+            group_by_interval,
+            project_id,
+            time_range,
+        )
+        .await;
+    }
+
+    let query_string = build_trace_latency_metric_query(
+        table,
+        aggregation,
+        group_by_interval,
+        &time_range,
+        negative_latency,
+    );
+
+    execute_project_query(&clickhouse, options, &query_string, &[project_id]).await
+}
+
+pub fn build_trace_latency_metric_query(
+    table: &SpanTable,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    negative_latency: NegativeLatencyPolicy,
+) -> String {
+    let metric = format!(
+        "toFloat64({})",
+        negative_latency.to_ch_latency(NumericColumn::TraceLatencySeconds.to_ch_trace_expression())
+    );
+    span_metric_query(
+        table,
+        group_by_interval,
+        time_range,
+        aggregation,
+        &metric,
+        &[],
+        false,
+    )
+}
+
+/// Maximum span depth per trace, aggregated per bucket. Agent traces with deep nesting
+/// usually indicate more reasoning steps.
+pub async fn get_trace_depth_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_numeric_metric(
+        clickhouse,
+        table,
+        options,
+        NumericColumn::TraceDepth,
+        aggregation,
+        group_by_interval,
+        project_id,
+        time_range,
+    )
+    .await
+}
+
+pub async fn get_total_token_count_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+) -> Result<Vec<IntMetricTimeValue>> {
+    let values = get_numeric_metric(
+        clickhouse,
+        table,
+        options,
+        NumericColumn::TotalTokens,
+        aggregation,
+        group_by_interval,
+        project_id,
+        time_range,
+    )
+    .await?;
+
+    Ok(to_int_metric_values(values))
+}
+
+/// Prompt tokens per trace, aggregated per bucket. Together with
+/// `get_completion_token_metrics` this splits `get_total_token_count_metrics` into input
+/// and output.
+pub async fn get_prompt_token_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+) -> Result<Vec<IntMetricTimeValue>> {
+    let values = get_numeric_metric(
+        clickhouse,
+        table,
+        options,
+        NumericColumn::PromptTokens,
+        aggregation,
+        group_by_interval,
+        project_id,
+        time_range,
+    )
+    .await?;
+
+    Ok(to_int_metric_values(values))
+}
+
+/// Generated tokens per trace, aggregated per bucket, e.g. to size output-heavy workloads
+pub async fn get_completion_token_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+) -> Result<Vec<IntMetricTimeValue>> {
+    let values = get_numeric_metric(
+        clickhouse,
+        table,
+        options,
+        NumericColumn::CompletionTokens,
+        aggregation,
+        group_by_interval,
+        project_id,
+        time_range,
+    )
+    .await?;
+
+    Ok(to_int_metric_values(values))
+}
+
+/// Cost per bucket, in USD or converted with `fx_rate`. With `cumulative`, each bucket
+/// holds the running total since the start of the time range instead, e.g. for budget
+/// burndown charts.
+pub async fn get_cost_usd_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    aggregation: Aggregation,
+    fx_rate: Option<FxRate>,
+    cumulative: bool,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    if fx_rate.is_none() && !cumulative && table.as_str() == SpanTable::default().as_str() {
+        return execute_dashboard_query(
+            &clickhouse,
+            options,
+            DashboardMetric::TotalCost,
+            aggregation,
+            group_by_interval,
+            project_id,
+            &time_range,
+        )
+        .await;
+    }
+
+    MetricQuery::new(project_id, NumericColumn::TotalCost)
+        .table(table.clone())
+        .options(options.clone())
+        .time_range(time_range)
+        .interval(group_by_interval)
+        .aggregation(aggregation)
+        .fx_rate(fx_rate)
+        .cost_precision(options.cost_precision())
+        .cumulative(cumulative)
+        .execute(clickhouse)
+        .await
+}
+
+/// Buckets of the total cost series whose value exceeds the mean of the series by more
+/// than `z_threshold` standard deviations, e.g. for alerting. Empty buckets are part of
+/// the series, and a constant series has no anomalies.
+///
+/// Buckets are `FloatMetricTimeValue`s rather than `IntMetricTimeValue`s, which would
+/// truncate costs to whole dollars. The window is a `TimeRange` like in the other metric
+/// functions, `TimeRange::Relative { past_hours }` for the past hours.
+pub async fn detect_cost_anomalies(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+    z_threshold: f64,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let values = get_cost_usd_metrics(
+        clickhouse,
+        table,
+        options,
+        group_by_interval,
+        project_id,
+        time_range,
+        Aggregation::Total,
+        None,
+        false,
+    )
+    .await?;
+    if values.is_empty() {
+        return Ok(values);
+    }
+
+    let count = values.len() as f64;
+    let mean = values.iter().map(|value| value.value).sum::<f64>() / count;
+    let variance = values
+        .iter()
+        .map(|value| (value.value - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    let stddev = variance.sqrt();
+    if stddev == 0.0 {
+        return Ok(Vec::new());
+    }
+
+    let threshold = mean + z_threshold * stddev;
+    Ok(values
+        .into_iter()
+        .filter(|value| value.value > threshold)
+        .collect())
+}
+
+#[deprecated(note = "use `get_total_trace_count_metrics` with a `TimeRange` instead")]
+pub async fn get_total_trace_count_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_total_trace_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+        1,
+        CountMode::Exact,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_total_trace_count_metrics` with a `TimeRange` instead")]
+pub async fn get_total_trace_count_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_total_trace_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+        1,
+        CountMode::Exact,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_distinct_span_names_metrics` with a `TimeRange` instead")]
+pub async fn get_distinct_span_names_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_distinct_span_names_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_distinct_span_names_metrics` with a `TimeRange` instead")]
+pub async fn get_distinct_span_names_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_distinct_span_names_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_cost_metrics_by_name` with a `TimeRange` instead")]
+pub async fn get_cost_metrics_by_name_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+    limit_top_n: u64,
+) -> Result<Vec<NameMetricTimeValue>> {
+    get_cost_metrics_by_name(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+        limit_top_n,
+        None,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_cost_metrics_by_name` with a `TimeRange` instead")]
+pub async fn get_cost_metrics_by_name_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    limit_top_n: u64,
+) -> Result<Vec<NameMetricTimeValue>> {
+    get_cost_metrics_by_name(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+        limit_top_n,
+        None,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_cost_per_1k_tokens_metrics` with a `TimeRange` instead")]
+pub async fn get_cost_per_1k_tokens_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_cost_per_1k_tokens_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_cost_per_1k_tokens_metrics` with a `TimeRange` instead")]
+pub async fn get_cost_per_1k_tokens_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_cost_per_1k_tokens_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_io_cost_ratio_metrics` with a `TimeRange` instead")]
+pub async fn get_io_cost_ratio_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_io_cost_ratio_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_io_cost_ratio_metrics` with a `TimeRange` instead")]
+pub async fn get_io_cost_ratio_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_io_cost_ratio_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_non_llm_span_count_metrics` with a `TimeRange` instead")]
+pub async fn get_non_llm_span_count_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_non_llm_span_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_non_llm_span_count_metrics` with a `TimeRange` instead")]
+pub async fn get_non_llm_span_count_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_non_llm_span_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_llm_span_count_metrics` with a `TimeRange` instead")]
+pub async fn get_llm_span_count_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_llm_span_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_llm_span_count_metrics` with a `TimeRange` instead")]
+pub async fn get_llm_span_count_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_llm_span_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_trace_latency_seconds_metrics` with a `TimeRange` instead")]
+pub async fn get_trace_latency_seconds_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_trace_latency_seconds_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+        aggregation,
+        NegativeLatencyPolicy::default(),
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_trace_latency_seconds_metrics` with a `TimeRange` instead")]
+pub async fn get_trace_latency_seconds_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_trace_latency_seconds_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+        aggregation,
+        NegativeLatencyPolicy::default(),
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_trace_depth_metrics` with a `TimeRange` instead")]
+pub async fn get_trace_depth_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_trace_depth_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+        aggregation,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_trace_depth_metrics` with a `TimeRange` instead")]
+pub async fn get_trace_depth_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_trace_depth_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+        aggregation,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_total_token_count_metrics` with a `TimeRange` instead")]
+pub async fn get_total_token_count_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+    aggregation: Aggregation,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_total_token_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+        aggregation,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_total_token_count_metrics` with a `TimeRange` instead")]
+pub async fn get_total_token_count_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    aggregation: Aggregation,
+) -> Result<Vec<IntMetricTimeValue>> {
+    get_total_token_count_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+        aggregation,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_cost_usd_metrics` with a `TimeRange` instead")]
+pub async fn get_cost_usd_metrics_relative(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    past_hours: i64,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_cost_usd_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Relative { past_hours },
+        aggregation,
+        None,
+        false,
+    )
+    .await
+}
+
+#[deprecated(note = "use `get_cost_usd_metrics` with a `TimeRange` instead")]
+pub async fn get_cost_usd_metrics_absolute(
+    clickhouse: clickhouse::Client,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    aggregation: Aggregation,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    get_cost_usd_metrics(
+        clickhouse,
+        &SpanTable::default(),
+        &QueryOptions::default(),
+        group_by_interval,
+        project_id,
+        TimeRange::Absolute {
+            start: start_time,
+            end: end_time,
+        },
+        aggregation,
+        None,
+        false,
+    )
+    .await
+}
+
+async fn get_numeric_metric(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    column: NumericColumn,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    if let Some(metric) = DashboardMetric::from_numeric_column(column) {
+        if table.as_str() == SpanTable::default().as_str() {
+            return execute_dashboard_query(
+                &clickhouse,
+                options,
+                metric,
+                aggregation,
+                group_by_interval,
+                project_id,
+                &time_range,
+            )
+            .await;
+        }
+    }
+
+    let query_string =
+        build_numeric_metric_query(table, column, aggregation, group_by_interval, &time_range);
+
+    execute_project_query(&clickhouse, options, &query_string, &[project_id]).await
+}
+
+/// Runs one of the cached dashboard templates over the default spans table
+async fn execute_dashboard_query<T>(
+    clickhouse: &clickhouse::Client,
+    options: &QueryOptions,
+    metric: DashboardMetric,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: &TimeRange,
+) -> Result<Vec<T>>
+where
+    T: Row + for<'a> Deserialize<'a>,
+{
+    let template = dashboard_query_template(metric, aggregation, group_by_interval, time_range);
+    let query = bind_dashboard_query(
+        options.apply(clickhouse).query(template),
+        metric,
+        project_id,
+        options.cost_precision(),
+        time_range,
+    );
+
+    options
+        .run(clickhouse, async { Ok(query.fetch_all::<T>().await?) })
+        .await
+}
+
+/// Final SQL of a numeric metric, useful for debugging it in the ClickHouse client. The
+/// project ids are left as a placeholder, see `span_metric_query`.
+pub fn build_numeric_metric_query(
+    table: &SpanTable,
+    column: NumericColumn,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> String {
+    if let NumericColumn::TraceLatencySeconds = column {
+        return build_trace_latency_metric_query(
+            table,
+            aggregation,
+            group_by_interval,
+            time_range,
+            NegativeLatencyPolicy::default(),
+        );
+    }
+
+    let metric = format!("toFloat64({})", column.to_ch_trace_expression());
+    span_metric_query(
+        table,
+        group_by_interval,
+        time_range,
+        aggregation,
+        &metric,
+        &[],
+        false,
+    )
+}
+
+/// Several aggregations of a numeric metric in one query, e.g. the total and the median
+/// overlaid on one chart. `values[i]` of each bucket is `aggregations[i]`, and all of
+/// them share the same buckets.
+///
+/// Fails if `aggregations` is empty or contains duplicates.
+pub async fn get_numeric_metric_multi(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    column: NumericColumn,
+    aggregations: &[Aggregation],
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<MultiMetricTimeValue>> {
+    if aggregations.is_empty() {
+        return Err(anyhow::anyhow!("At least one aggregation is required"));
+    }
+    let mut seen = std::collections::HashSet::new();
+    if let Some(duplicate) = aggregations
+        .iter()
+        .find(|aggregation| !seen.insert(**aggregation))
+    {
+        return Err(anyhow::anyhow!(
+            "Aggregation {:?} is requested twice",
+            duplicate
+        ));
+    }
+
+    let query_string = build_numeric_metric_multi_query(
+        table,
+        column,
+        aggregations,
+        group_by_interval,
+        project_id,
+        &time_range,
+        options.cost_precision(),
+    );
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+pub fn build_numeric_metric_multi_query(
+    table: &SpanTable,
+    column: NumericColumn,
+    aggregations: &[Aggregation],
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: &TimeRange,
+    cost_precision: u32,
+) -> String {
+    let traces_cte = per_trace_cte(
+        table,
+        group_by_interval,
+        &format!("toFloat64({})", column.to_ch_trace_expression()),
+        &[format!("project_id = '{project_id}'")],
+    );
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+    let aliases = (0..aggregations.len())
+        .map(|i| format!("value_{i}"))
+        .collect::<Vec<String>>();
+    let value_columns = aggregations
+        .iter()
+        .zip(&aliases)
+        .map(|(aggregation, alias)| {
+            let value = format!("ifNull({}(value), 0)", aggregation.to_ch_agg_function());
+            let value = match column {
+                NumericColumn::TotalCost => to_ch_rounded_cost(&value, cost_precision),
+                _ => value,
+            };
+            format!("toFloat64({value}) AS {alias}")
+        })
+        .collect::<Vec<String>>()
+        .join(",\n            ");
+    let aliases = aliases.join(", ");
+
+    // Filled before the values are collected into an array, since WITH FILL would fill
+    // the array of added buckets with an empty one
+    format!(
+        "
+    WITH {traces_cte}
+    SELECT
+        time,
+        [{aliases}] AS values
+    FROM (
+        SELECT
+            time,
+            {value_columns}
+        FROM traces
+        WHERE {time_condition}
+        {group_by_time_statement}
+    )
+    ORDER BY time"
+    )
+}
+
+/// Same as the single-project numeric metrics, but aggregated over all `project_ids`
+pub async fn get_numeric_metrics_for_projects(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    column: NumericColumn,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    project_ids: &[Uuid],
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    if project_ids.is_empty() {
+        return Err(anyhow::anyhow!("At least one project id is required"));
+    }
+
+    let metric = format!("toFloat64({})", column.to_ch_trace_expression());
+    let query_string = span_metric_query(
+        table,
+        group_by_interval,
+        &time_range,
+        aggregation,
+        &metric,
+        &[],
+        false,
+    );
+
+    execute_project_query(&clickhouse, options, &query_string, project_ids).await
+}
+
+/// Numeric metrics for several projects at once, with a separate series per project
+pub async fn get_numeric_metrics_by_project(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    column: NumericColumn,
     aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    project_ids: &[Uuid],
+    time_range: TimeRange,
+) -> Result<Vec<ProjectMetricTimeValue>> {
+    if project_ids.is_empty() {
+        return Err(anyhow::anyhow!("At least one project id is required"));
+    }
+
+    let metric = format!("toFloat64({})", column.to_ch_trace_expression());
+    let query_string = span_metric_query(
+        table,
+        group_by_interval,
+        &time_range,
+        aggregation,
+        &metric,
+        &[],
+        true,
+    );
+
+    execute_project_query(&clickhouse, options, &query_string, project_ids).await
+}
+
+fn to_int_metric_values(values: Vec<FloatMetricTimeValue>) -> Vec<MetricTimeValue<i64>> {
+    values
+        .into_iter()
+        .map(|v| MetricTimeValue {
+            time: v.time,
+            value: v.value.round() as i64,
+        })
+        .collect()
+}
+
+/// `traces` CTE shared by the per-trace metrics, with one row per trace bucketed by its
+/// earliest span and `metric` computed over its spans matching all `span_conditions`.
+/// Time conditions go outside of it, so that the CTE is the same for every time range.
+pub(super) fn per_trace_cte(
+    table: &impl fmt::Display,
+    group_by_interval: GroupByInterval,
     metric: &str,
+    span_conditions: &[String],
 ) -> String {
     let ch_round_time = group_by_interval.to_ch_truncate_time();
-    let ch_aggregation = aggregation.to_ch_agg_function();
+    let span_where_statement = if span_conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", span_conditions.join(" AND "))
+    };
 
     format!(
-        "
-    WITH traces AS (
+        "traces AS (
     SELECT
         trace_id,
         project_id,
         {ch_round_time}(MIN(start_time)) as time,
         {metric} as value
-    FROM spans
+    FROM {table}
+    {span_where_statement}
     GROUP BY project_id, trace_id
+    )"
     )
+}
+
+/// Per-trace metric aggregated per bucket. `span_conditions` restrict which spans of a
+/// trace contribute to its value.
+///
+/// The project ids are left as the `PROJECT_IDS_CONDITION` placeholder after those of
+/// `span_conditions`, e.g. for `execute_project_query` to bind.
+pub(super) fn span_metric_query(
+    table: &impl fmt::Display,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    aggregation: Aggregation,
+    metric: &str,
+    span_conditions: &[String],
+    group_by_project: bool,
+) -> String {
+    let traces_cte = per_trace_cte(table, group_by_interval, metric, span_conditions);
+    let ch_aggregation = aggregation.to_ch_agg_function();
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+    let (project_column, group_by_statement) = if group_by_project {
+        (
+            "project_id,",
+            format!(
+                "GROUP BY
+            time, project_id
+        ORDER BY
+            project_id, time
+        {}",
+                time_range.to_ch_with_fill(group_by_interval)
+            ),
+        )
+    } else {
+        ("", group_by_time_statement)
+    };
+
+    format!(
+        "
+    WITH {traces_cte}
     SELECT
         time,
-        {ch_aggregation}(value) as value
+        {project_column}
+        ifNull({ch_aggregation}(value), 0) as value
     FROM traces
+    WHERE
+        {PROJECT_IDS_CONDITION}
+        AND {time_condition}
+    {group_by_statement}"
+    )
+}
+
+/// Final SQL of a span-level metric, useful for debugging it in the ClickHouse client
+pub fn build_span_level_metric_query(
+    table: &SpanTable,
+    metric: SpanLevelMetric,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: &TimeRange,
+) -> String {
+    let metric = metric.to_ch_expression();
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let time_condition = time_range.to_ch_condition("start_time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+
+    format!(
+        "
+    SELECT
+        {ch_round_time}(start_time) AS time,
+        {metric} AS value
+    FROM {table}
     WHERE
         project_id = '{project_id}'
-        AND time >= now() - INTERVAL {past_hours} HOUR
-    {}",
-        group_by_time_relative_statement(past_hours, group_by_interval)
+        AND {time_condition}
+    {group_by_time_statement}"
     )
 }
 
-fn span_metric_query_absolute(
+/// Final SQL of the cost by span name metric
+pub fn build_cost_by_name_query(
+    table: &SpanTable,
     project_id: Uuid,
     group_by_interval: GroupByInterval,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-    aggregation: Aggregation,
-    metric: &str,
+    time_range: &TimeRange,
+    limit_top_n: u64,
+    fx_rate: Option<FxRate>,
+    cost_precision: u32,
+) -> String {
+    top_n_cost_query(
+        table,
+        project_id,
+        group_by_interval,
+        time_range,
+        "name",
+        false,
+        limit_top_n,
+        fx_rate,
+        cost_precision,
+    )
+}
+
+/// Final SQL of the cost by session metric
+pub fn build_cost_by_session_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    limit_top_n: u64,
+    fx_rate: Option<FxRate>,
+    cost_precision: u32,
+) -> String {
+    top_n_cost_query(
+        table,
+        project_id,
+        group_by_interval,
+        time_range,
+        "session_id",
+        true,
+        limit_top_n,
+        fx_rate,
+        cost_precision,
+    )
+}
+
+/// Final SQL of the cost by provider metric
+pub fn build_cost_by_provider_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    limit_top_n: u64,
+    fx_rate: Option<FxRate>,
+    cost_precision: u32,
+) -> String {
+    top_n_cost_query(
+        table,
+        project_id,
+        group_by_interval,
+        time_range,
+        "provider",
+        true,
+        limit_top_n,
+        fx_rate,
+        cost_precision,
+    )
+}
+
+/// Cost per value of `column` and bucket, where only the `limit_top_n` most expensive
+/// values get their own series and the rest are rolled up into `"other"`
+fn top_n_cost_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    column: &str,
+    exclude_null_sentinel: bool,
+    limit_top_n: u64,
+    fx_rate: Option<FxRate>,
+    cost_precision: u32,
 ) -> String {
     let ch_round_time = group_by_interval.to_ch_truncate_time();
-    let ch_start_time = start_time.timestamp();
-    let ch_end_time = end_time.timestamp();
-    let ch_aggregation = aggregation.to_ch_agg_function();
+    let cost = FxRate::to_ch_cost(fx_rate, "SUM(total_cost)");
+    let value = to_ch_rounded_cost(&format!("ifNull({cost}, 0)"), cost_precision);
+    let time_condition = time_range.to_ch_condition("start_time");
+    let with_fill_statement = time_range.to_ch_with_fill(group_by_interval);
+    let null_condition = if exclude_null_sentinel {
+        format!("AND {column} != '{NULL_SENTINEL}'")
+    } else {
+        String::new()
+    };
+
+    format!(
+        "
+    WITH top_values AS (
+        SELECT {column}
+        FROM {table}
+        WHERE
+            project_id = '{project_id}'
+            AND {time_condition}
+            {null_condition}
+        GROUP BY {column}
+        ORDER BY SUM(total_cost) DESC
+        LIMIT {limit_top_n}
+    )
+    SELECT
+        time,
+        series_name AS {column},
+        value
+    FROM (
+        SELECT
+            {ch_round_time}(start_time) AS time,
+            if({column} IN (SELECT {column} FROM top_values), {column}, 'other') AS series_name,
+            {value} AS value
+        FROM {table}
+        WHERE
+            project_id = '{project_id}'
+            AND {time_condition}
+            {null_condition}
+        GROUP BY time, series_name
+    )
+    ORDER BY {column}, time
+    {with_fill_statement}"
+    )
+}
+
+/// Final SQL of the usage by model metric
+pub fn build_model_usage_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    include_io_cost: bool,
+    fx_rate: Option<FxRate>,
+    cost_precision: u32,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let time_condition = time_range.to_ch_condition("start_time");
+    let with_fill_statement = time_range.to_ch_with_fill(group_by_interval);
+    let cost = |column: &str| {
+        to_ch_rounded_cost(
+            &FxRate::to_ch_cost(fx_rate, &format!("SUM({column})")),
+            cost_precision,
+        )
+    };
+    let total_cost = cost("total_cost");
+    let (input_cost, output_cost) = if include_io_cost {
+        (
+            format!("toNullable({})", cost("input_cost")),
+            format!("toNullable({})", cost("output_cost")),
+        )
+    } else {
+        (
+            "CAST(NULL AS Nullable(Float64))".to_string(),
+            "CAST(NULL AS Nullable(Float64))".to_string(),
+        )
+    };
 
     format!(
         "
+    SELECT
+        {ch_round_time}(start_time) AS time,
+        model,
+        SUM(prompt_tokens) AS prompt_tokens,
+        SUM(completion_tokens) AS completion_tokens,
+        SUM(total_tokens) AS total_tokens,
+        {total_cost} AS total_cost,
+        {input_cost} AS input_cost,
+        {output_cost} AS output_cost
+    FROM {table}
+    WHERE
+        project_id = '{project_id}'
+        AND model != '{NULL_SENTINEL}'
+        AND {time_condition}
+    GROUP BY time, model
+    ORDER BY model, time
+    {with_fill_statement}"
+    )
+}
+
+/// Final SQL of the latency by provider metric
+pub fn build_latency_by_provider_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    aggregation: Aggregation,
+    grouping: ProviderLatencyGrouping,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let ch_aggregation = aggregation.to_ch_agg_function();
+    let with_fill_statement = time_range.to_ch_with_fill(group_by_interval);
+
+    match grouping {
+        ProviderLatencyGrouping::DominantProvider => {
+            let latency = NumericColumn::TraceLatencySeconds.to_ch_trace_expression();
+            let time_condition = time_range.to_ch_condition("time");
+            format!(
+                "
     WITH traces AS (
     SELECT
         trace_id,
-        project_id,
         {ch_round_time}(MIN(start_time)) as time,
-        {metric} as value
-    FROM spans
-    GROUP BY project_id, trace_id
+        topKIf(1)(provider, provider != '{NULL_SENTINEL}') as top_providers,
+        toFloat64({latency}) as value
+    FROM {table}
+    WHERE project_id = '{project_id}'
+    GROUP BY trace_id
     )
     SELECT
         time,
-        {ch_aggregation}(value) as value
+        top_providers[1] AS provider,
+        ifNull({ch_aggregation}(value), 0) AS value
     FROM traces
+    WHERE
+        notEmpty(top_providers)
+        AND {time_condition}
+    GROUP BY time, provider
+    ORDER BY provider, time
+    {with_fill_statement}"
+            )
+        }
+        ProviderLatencyGrouping::PerSpan => {
+            let time_condition = time_range.to_ch_condition("start_time");
+            format!(
+                "
+    SELECT
+        {ch_round_time}(start_time) AS time,
+        provider,
+        ifNull({ch_aggregation}(
+            (toUnixTimestamp64Nano(end_time) - toUnixTimestamp64Nano(start_time)) / 1e9
+        ), 0) AS value
+    FROM {table}
     WHERE
         project_id = '{project_id}'
-        AND time >= fromUnixTimestamp({ch_start_time})
-        AND time <= fromUnixTimestamp({ch_end_time})
-    {}",
-        group_by_time_absolute_statement(start_time, end_time, group_by_interval)
+        AND provider != '{NULL_SENTINEL}'
+        AND {time_condition}
+    GROUP BY time, provider
+    ORDER BY provider, time
+    {with_fill_statement}"
+            )
+        }
+    }
+}
+
+/// Final SQL of the span gap metric.
+///
+/// Spans nest, so subtracting the summed span durations from the trace latency would
+/// count children twice. Instead, with spans ordered by start time, a gap is the time
+/// between a span's start and the latest end of all spans before it.
+pub fn build_span_gap_metric_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+    aggregation: Aggregation,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let ch_aggregation = aggregation.to_ch_agg_function();
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+
+    format!(
+        "
+    WITH span_gaps AS (
+    SELECT
+        trace_id,
+        start_time,
+        row_number() OVER (PARTITION BY trace_id ORDER BY start_time) as span_index,
+        max(end_time) OVER (
+            PARTITION BY trace_id
+            ORDER BY start_time
+            ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+        ) as covered_until
+    FROM {table}
+    WHERE project_id = '{project_id}'
+    ),
+    traces AS (
+    SELECT
+        trace_id,
+        {ch_round_time}(MIN(start_time)) as time,
+        SUM(if(
+            span_index > 1,
+            greatest(toUnixTimestamp64Nano(start_time) - toUnixTimestamp64Nano(covered_until), 0),
+            0
+        )) / 1e9 as value
+    FROM span_gaps
+    GROUP BY trace_id
+    )
+    SELECT
+        time,
+        ifNull({ch_aggregation}(value), 0) as value
+    FROM traces
+    WHERE {time_condition}
+    {group_by_time_statement}"
+    )
+}
+
+/// Average number of child spans per parent span. Only spans with at least one child
+/// count as parents, and each span counts toward the fan-out of its own parent only, so
+/// root spans contribute as parents but never as children. Parents are bucketed by the
+/// start of their earliest child.
+pub async fn get_span_fan_out_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let query_string =
+        build_span_fan_out_metric_query(table, project_id, group_by_interval, &time_range);
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+pub fn build_span_fan_out_metric_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+    let nil_span_id = Uuid::nil();
+
+    format!(
+        "
+    WITH parents AS (
+    SELECT
+        trace_id,
+        parent_span_id,
+        {ch_round_time}(MIN(start_time)) as time,
+        toFloat64(count()) as value
+    FROM {table}
+    WHERE project_id = '{project_id}'
+        AND parent_span_id != '{nil_span_id}'
+    GROUP BY trace_id, parent_span_id
+    )
+    SELECT
+        time,
+        ifNull(AVG(value), 0) as value
+    FROM parents
+    WHERE {time_condition}
+    {group_by_time_statement}"
+    )
+}
+
+/// Share of traces per bucket, from 0 to 1, with at least one span whose status is
+/// `error`, e.g. for SLO reporting
+pub async fn get_error_trace_ratio_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    require_column(
+        &clickhouse,
+        table.as_str(),
+        "status",
+        "006000-span-status.sql",
+    )
+    .await?;
+    let query_string = build_error_trace_ratio_metric_query(table, group_by_interval, &time_range);
+
+    execute_project_query(&clickhouse, options, &query_string, &[project_id]).await
+}
+
+pub fn build_error_trace_ratio_metric_query(
+    table: &SpanTable,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> String {
+    // The average of the per-trace 0/1 flags is countIf(trace_has_error) / count()
+    span_metric_query(
+        table,
+        group_by_interval,
+        time_range,
+        Aggregation::Average,
+        "toFloat64(max(status = 'error'))",
+        &[],
+        false,
+    )
+}
+
+/// Peak number of spans in flight at the same time per bucket, i.e. the maximum number
+/// of overlapping `[start_time, end_time]` intervals, e.g. to size ingestion capacity.
+///
+/// Computed as a sweep over +1/-1 events at the span starts and ends. A bucket without
+/// any event reports 0 even if long-running spans cover it.
+pub async fn get_span_concurrency_metrics(
+    clickhouse: clickhouse::Client,
+    table: &SpanTable,
+    options: &QueryOptions,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<IntMetricTimeValue>> {
+    let query_string =
+        build_span_concurrency_metric_query(table, project_id, group_by_interval, &time_range);
+
+    execute_query(&clickhouse, options, &query_string).await
+}
+
+pub fn build_span_concurrency_metric_query(
+    table: &SpanTable,
+    project_id: Uuid,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> String {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let (range_start, range_end) = time_range.to_ch_bounds();
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+
+    // Spans that started before the range are kept so that the running sum counts them
+    // as in flight at its start. Starts sort before ends at the same instant, since the
+    // intervals are closed. The count before each event is part of the bucket maximum, so
+    // that a bucket with only end events reports the spans carried into it.
+    format!(
+        "
+    WITH span_intervals AS (
+    SELECT
+        start_time,
+        end_time
+    FROM {table}
+    WHERE project_id = '{project_id}'
+        AND start_time <= {range_end}
+        AND end_time >= {range_start}
+    ),
+    span_events AS (
+    SELECT start_time AS event_time, 1 AS delta FROM span_intervals
+    UNION ALL
+    SELECT end_time AS event_time, -1 AS delta FROM span_intervals
+    ),
+    in_flight AS (
+    SELECT
+        {ch_round_time}(event_time) as time,
+        delta,
+        sum(delta) OVER (
+            ORDER BY event_time ASC, delta DESC
+            ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+        ) AS running
+    FROM span_events
+    )
+    SELECT
+        time,
+        toInt64(ifNull(MAX(greatest(running, running - delta)), 0)) as value
+    FROM in_flight
+    WHERE {time_condition}
+    {group_by_time_statement}"
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use std::collections::HashMap;
+
+    use serde_json::Value;
+
+    use super::{
+        build_cost_by_session_query, build_numeric_metric_query,
+        build_span_concurrency_metric_query, build_span_latency_metric_query,
+        build_span_level_metric_query, build_trace_count_metric_query,
+        build_trace_latency_metric_query, distributed_span_table_ddl, empty_bucket_ranges,
+        insert_columns, per_trace_cte, CHSpan, NumericColumn, SpanLevelMetric, SpanTable,
+        DEFAULT_INGEST_SOURCE, NULL_SENTINEL, SPAN_LATENCY_SECONDS,
+    };
+    use crate::ch::{
+        modifiers::{GroupByInterval, NegativeLatencyPolicy, TimeRange},
+        Aggregation, CountMode, IntMetricTimeValue,
+    };
+
+    #[test]
+    fn test_insert_columns_skip_nulls() {
+        let span = CHSpan {
+            span_id: Uuid::new_v4(),
+            name: String::from("llm"),
+            span_type: 1,
+            start_time: 1_000,
+            end_time: 2_000,
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            total_tokens: 15,
+            input_cost: 0.1,
+            output_cost: 0.2,
+            total_cost: 0.3,
+            model: String::from("gpt-4o"),
+            session_id: String::from(NULL_SENTINEL),
+            project_id: Uuid::new_v4(),
+            trace_id: Uuid::new_v4(),
+            provider: String::from("openai"),
+            user_id: String::from(NULL_SENTINEL),
+            path: String::from(NULL_SENTINEL),
+            depth: 0,
+            updated_at: 0,
+            parent_span_id: Uuid::nil(),
+            cost_known: true,
+            status: String::from("ok"),
+            attributes: vec![(String::from("key"), String::from("value"))],
+            ingest_source: String::from(DEFAULT_INGEST_SOURCE),
+            cached_tokens: None,
+        };
+        let extra = HashMap::from([
+            (String::from("region"), Value::from("eu")),
+            (String::from("tenant"), Value::Null),
+        ]);
+
+        let columns = insert_columns(&span, &extra, 3_000).unwrap();
+        let names = columns.names.split(", ").collect::<Vec<&str>>();
+
+        assert!(!names.contains(&"cached_tokens"));
+        assert!(!names.contains(&"tenant"));
+        assert!(names.contains(&"region"));
+        assert!(columns.binds.iter().all(|value| !value.is_null()));
+        // The attributes map takes two placeholders, for its keys and its values
+        assert_eq!(
+            columns.placeholders.matches('?').count(),
+            columns.binds.len()
+        );
+        assert_eq!(columns.binds.len(), names.len() + 1);
+        // start_time, end_time and updated_at
+        assert_eq!(
+            columns
+                .placeholders
+                .matches("fromUnixTimestamp64Nano(?)")
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_span_concurrency_counts_spans_carried_into_bucket() {
+        let query = build_span_concurrency_metric_query(
+            &SpanTable::default(),
+            Uuid::nil(),
+            GroupByInterval::Hour,
+            &TimeRange::Relative { past_hours: 24 },
+        );
+
+        // The CTEs must not shadow the spans and events tables they are read next to
+        assert!(query.contains("WITH span_intervals AS ("));
+        assert!(query.contains("span_events AS ("));
+        assert!(query.contains("FROM spans\n"));
+        assert!(!query.contains("WITH spans AS"));
+        assert!(!query.contains(" events AS ("));
+        // Two spans started in one bucket and both ended in the next: the second bucket
+        // only has -1 events, running 1 then 0, and its peak of 2 is the count before
+        // the first of them
+        assert!(query.contains("MAX(greatest(running, running - delta))"));
+    }
+
+    #[test]
+    fn test_span_table_name_validation() {
+        assert!(SpanTable::new("spans_staging").is_ok());
+        assert!(SpanTable::new("spans; DROP TABLE spans").is_err());
+        assert!(SpanTable::new("1spans").is_err());
+        assert!(SpanTable::new("").is_err());
+    }
+
+    #[test]
+    fn test_distributed_span_table_ddl() {
+        let local_table = SpanTable::new("spans_local").unwrap();
+        let ddl =
+            distributed_span_table_ddl("laminar", &local_table, &SpanTable::default()).unwrap();
+
+        assert!(ddl.contains("CREATE TABLE IF NOT EXISTS spans ON CLUSTER laminar"));
+        assert!(ddl.contains(
+            "Distributed(laminar, currentDatabase(), spans_local, cityHash64(project_id))"
+        ));
+        assert!(
+            distributed_span_table_ddl("laminar; DROP", &local_table, &SpanTable::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_numeric_metric_query() {
+        let query = build_numeric_metric_query(
+            &SpanTable::new("spans_staging").unwrap(),
+            NumericColumn::TotalCost,
+            Aggregation::Total,
+            GroupByInterval::Hour,
+            &TimeRange::Relative { past_hours: 24 },
+        );
+
+        assert!(query.contains("toFloat64(SUM(total_cost)) as value"));
+        assert!(query.contains("FROM spans_staging"));
+        assert!(query.contains("project_id IN ?"));
+        assert!(query.contains("time >= now() - INTERVAL 24 HOUR"));
+        assert!(query.contains("ifNull(SUM(value), 0) as value"));
+    }
+
+    #[test]
+    fn test_span_level_metric_query() {
+        let query = build_span_level_metric_query(
+            &SpanTable::default(),
+            SpanLevelMetric::LlmSpanCount,
+            GroupByInterval::Minute,
+            Uuid::nil(),
+            &TimeRange::Relative { past_hours: 1 },
+        );
+
+        assert!(query.contains("toStartOfMinute(start_time) AS time"));
+        assert!(query.contains("toInt64(countIf(total_tokens > 0)) AS value"));
+        assert!(query.contains("start_time >= now() - INTERVAL 1 HOUR"));
+        assert!(query.contains("STEP toIntervalMinute(1)"));
+    }
+
+    #[test]
+    fn test_metric_aggregates_default_to_zero() {
+        // Empty groups must yield 0 rather than NULL, which non-nullable rows can't hold
+        let columns = [
+            NumericColumn::TotalTokens,
+            NumericColumn::PromptTokens,
+            NumericColumn::CompletionTokens,
+            NumericColumn::TotalCost,
+            NumericColumn::TraceLatencySeconds,
+            NumericColumn::TraceDepth,
+        ];
+        for column in columns {
+            for aggregation in [
+                Aggregation::Total,
+                Aggregation::Average,
+                Aggregation::Median,
+            ] {
+                let ch_aggregation = aggregation.to_ch_agg_function().to_string();
+                let query = build_numeric_metric_query(
+                    &SpanTable::default(),
+                    column,
+                    aggregation,
+                    GroupByInterval::Hour,
+                    &TimeRange::Relative { past_hours: 24 },
+                );
+                assert!(query.contains(&format!("ifNull({ch_aggregation}(value), 0) as value")));
+            }
+        }
+
+        let query = build_cost_by_session_query(
+            &SpanTable::default(),
+            Uuid::nil(),
+            GroupByInterval::Hour,
+            &TimeRange::Relative { past_hours: 24 },
+            10,
+            None,
+            6,
+        );
+        assert!(query.contains("round(ifNull(SUM(total_cost), 0), 6) AS value"));
+    }
+
+    #[test]
+    fn test_trace_count_query_shape_matches_across_time_ranges() {
+        let end = chrono::Utc::now();
+        let ranges = [
+            TimeRange::Relative { past_hours: 24 },
+            TimeRange::Absolute {
+                start: end - chrono::Duration::hours(24),
+                end,
+            },
+        ];
+        let queries = ranges.map(|time_range| {
+            build_trace_count_metric_query(
+                &SpanTable::default(),
+                GroupByInterval::Hour,
+                Uuid::nil(),
+                &time_range,
+                1,
+                CountMode::Exact,
+            )
+        });
+
+        // Only the time condition and the filled range may differ
+        let shapes = queries.map(|query| query.split("WHERE").next().unwrap().to_string());
+        assert_eq!(shapes[0], shapes[1]);
+        assert!(!shapes[0].contains("total_tokens"));
+    }
+
+    #[test]
+    fn test_token_and_cost_queries_differ_only_in_time_window() {
+        let end = chrono::Utc::now();
+        let ranges = [
+            TimeRange::Relative { past_hours: 24 },
+            TimeRange::Absolute {
+                start: end - chrono::Duration::hours(24),
+                end,
+            },
+        ];
+
+        for column in [NumericColumn::TotalTokens, NumericColumn::TotalCost] {
+            let bodies = ranges.map(|time_range| {
+                let query = build_numeric_metric_query(
+                    &SpanTable::default(),
+                    column,
+                    Aggregation::Total,
+                    GroupByInterval::Hour,
+                    &time_range,
+                );
+                let time_condition = time_range.to_ch_condition("time");
+                let group_by_time_statement = time_range.to_ch_group_by_time(GroupByInterval::Hour);
+                assert!(query.contains(&time_condition));
+                assert!(query.contains(&group_by_time_statement));
+
+                query
+                    .replace(&time_condition, "")
+                    .replace(&group_by_time_statement, "")
+            });
+
+            assert_eq!(bodies[0], bodies[1]);
+            assert!(bodies[0].contains(&per_trace_cte(
+                &SpanTable::default(),
+                GroupByInterval::Hour,
+                &format!("toFloat64({})", column.to_ch_trace_expression()),
+                &[],
+            )));
+        }
+    }
+
+    #[test]
+    fn test_empty_bucket_ranges() {
+        let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let end = start + chrono::Duration::hours(6);
+        let counts = [3, 0, 0, 1, 0, 0]
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| IntMetricTimeValue {
+                time: (start.timestamp() + i as i64 * 3600) as u32,
+                value,
+            })
+            .collect::<Vec<_>>();
+        let hour = chrono::Duration::hours(1);
+
+        let gaps = empty_bucket_ranges(&counts, start, end, hour * 2);
+        assert_eq!(
+            gaps,
+            vec![(start + hour, start + hour * 3), (start + hour * 4, end)]
+        );
+
+        let gaps = empty_bucket_ranges(&counts, start, end, hour * 3);
+        assert!(gaps.is_empty());
+    }
+
+    #[test]
+    fn test_buckets_align_with_fill() {
+        let start = chrono::DateTime::from_timestamp(1_700_000_123, 456_789).unwrap();
+        let end = chrono::DateTime::from_timestamp(1_700_086_523, 0).unwrap();
+        for interval in [
+            GroupByInterval::Minute,
+            GroupByInterval::Hour,
+            GroupByInterval::Day,
+        ] {
+            let query = build_span_level_metric_query(
+                &SpanTable::default(),
+                SpanLevelMetric::LlmSpanCount,
+                interval,
+                Uuid::nil(),
+                &TimeRange::Absolute { start, end },
+            );
+            let ch_round_time = interval.to_ch_truncate_time();
+
+            // Buckets and the filled range must be truncated the same way, otherwise
+            // WITH FILL adds rows next to the real buckets instead of filling gaps
+            assert!(query.contains(&format!("{ch_round_time}(start_time) AS time")));
+            assert!(query.contains(&format!(
+                "FROM {ch_round_time}(fromUnixTimestamp({}))",
+                start.timestamp()
+            )));
+            assert!(query.contains(&format!(
+                "TO {ch_round_time}(fromUnixTimestamp({}) + INTERVAL {})",
+                end.timestamp(),
+                interval.to_interval()
+            )));
+            assert!(query.contains(&format!("STEP {}", interval.to_ch_step())));
+        }
+    }
+
+    #[test]
+    fn test_trace_latency_negative_latency_policy() {
+        let time_range = TimeRange::Relative { past_hours: 24 };
+        let query = |negative_latency| {
+            build_trace_latency_metric_query(
+                &SpanTable::default(),
+                Aggregation::Average,
+                GroupByInterval::Hour,
+                &time_range,
+                negative_latency,
+            )
+        };
+        let latency = NumericColumn::TraceLatencySeconds.to_ch_trace_expression();
+
+        assert!(query(NegativeLatencyPolicy::Exclude)
+            .contains(&format!("toFloat64(if(({latency}) < 0, NULL, {latency}))")));
+        assert!(query(NegativeLatencyPolicy::Clamp)
+            .contains(&format!("toFloat64(greatest({latency}, 0))")));
+        // An inverted span, ending before it starts, makes the span latency negative
+        let span_query = |negative_latency| {
+            build_span_latency_metric_query(
+                &SpanTable::default(),
+                Uuid::nil(),
+                GroupByInterval::Hour,
+                &time_range,
+                Aggregation::Average,
+                false,
+                negative_latency,
+            )
+        };
+        assert!(
+            span_query(NegativeLatencyPolicy::Exclude).contains(&format!(
+                "AVG(if(({SPAN_LATENCY_SECONDS}) < 0, NULL, {SPAN_LATENCY_SECONDS}))"
+            ))
+        );
+        assert!(span_query(NegativeLatencyPolicy::Clamp)
+            .contains(&format!("AVG(greatest({SPAN_LATENCY_SECONDS}, 0))")));
+        // The default matches what the numeric metrics use
+        assert_eq!(
+            query(NegativeLatencyPolicy::default()),
+            build_numeric_metric_query(
+                &SpanTable::default(),
+                NumericColumn::TraceLatencySeconds,
+                Aggregation::Average,
+                GroupByInterval::Hour,
+                &time_range,
+            )
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use super::{
+    modifiers::{GroupByInterval, TimeRange},
+    spans::{build_numeric_metric_query, NumericColumn, SpanTable},
+    utils::{
+        aggregate_table_backfilled, create_aggregate_table, execute_project_query,
+        refresh_aggregate_table, QueryOptions, PROJECT_IDS_CONDITION,
+    },
+    Aggregation, FloatMetricTimeValue,
+};
+
+/// Per-trace aggregates maintained by a materialized view over the spans table
+pub const TRACE_AGGREGATES_TABLE: &str = "trace_aggregates";
+const TRACE_AGGREGATES_VIEW: &str = "trace_aggregates_mv";
+
+/// Statements creating the `trace_aggregates` table and the materialized view that
+/// populates it on every insert into `span_table`.
+///
+/// Rows of the same trace are only combined when ClickHouse merges parts, so readers
+/// must still aggregate by trace.
+pub fn trace_aggregates_ddl(span_table: &SpanTable) -> Vec<String> {
+    vec![
+        format!(
+            "CREATE TABLE IF NOT EXISTS {TRACE_AGGREGATES_TABLE}
+            (
+                project_id UUID,
+                trace_id UUID,
+                min_start SimpleAggregateFunction(min, DateTime64(9, 'UTC')),
+                max_end SimpleAggregateFunction(max, DateTime64(9, 'UTC')),
+                sum_tokens SimpleAggregateFunction(sum, Int64),
+                sum_cost SimpleAggregateFunction(sum, Float64)
+            )
+            ENGINE = AggregatingMergeTree()
+            ORDER BY (project_id, trace_id)"
+        ),
+        format!(
+            "CREATE MATERIALIZED VIEW IF NOT EXISTS {TRACE_AGGREGATES_VIEW}
+            TO {TRACE_AGGREGATES_TABLE}
+            AS {}",
+            trace_aggregates_select(span_table)
+        ),
+    ]
+}
+
+fn trace_aggregates_select(span_table: &impl fmt::Display) -> String {
+    format!(
+        "SELECT
+            project_id,
+            trace_id,
+            MIN(start_time) AS min_start,
+            MAX(end_time) AS max_end,
+            SUM(total_tokens) AS sum_tokens,
+            SUM(total_cost) AS sum_cost
+        FROM {span_table}
+        GROUP BY project_id, trace_id"
+    )
+}
+
+pub async fn create_trace_aggregates(
+    clickhouse: clickhouse::Client,
+    span_table: &SpanTable,
+) -> Result<()> {
+    create_aggregate_table(
+        &clickhouse,
+        TRACE_AGGREGATES_TABLE,
+        &trace_aggregates_ddl(span_table),
+    )
+    .await
+}
+
+/// Backfills `trace_aggregates` with the traces of the spans table. The materialized view
+/// only sees spans inserted after it was created, so metrics keep reading the raw spans
+/// until this has run once.
+///
+/// Needs ingestion to be paused, see `refresh_aggregate_table`.
+pub async fn refresh_trace_aggregates(
+    clickhouse: clickhouse::Client,
+    span_table: &SpanTable,
+) -> Result<()> {
+    refresh_aggregate_table(&clickhouse, TRACE_AGGREGATES_TABLE, span_table, |source| {
+        trace_aggregates_select(&source)
+    })
+    .await
+}
+
+/// Numeric metric read from `trace_aggregates` if it has been backfilled and has the
+/// column, and from the raw spans otherwise
+pub async fn get_numeric_metric_from_trace_aggregates(
+    clickhouse: clickhouse::Client,
+    span_table: &SpanTable,
+    options: &QueryOptions,
+    column: NumericColumn,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    project_id: Uuid,
+    time_range: TimeRange,
+) -> Result<Vec<FloatMetricTimeValue>> {
+    let aggregates_query_string =
+        if aggregate_table_backfilled(&clickhouse, options, TRACE_AGGREGATES_TABLE).await? {
+            build_trace_aggregates_metric_query(column, aggregation, group_by_interval, &time_range)
+        } else {
+            None
+        };
+    let query_string = aggregates_query_string.unwrap_or_else(|| {
+        build_numeric_metric_query(
+            span_table,
+            column,
+            aggregation,
+            group_by_interval,
+            &time_range,
+        )
+    });
+
+    execute_project_query(&clickhouse, options, &query_string, &[project_id]).await
+}
+
+/// Returns `None` if `trace_aggregates` does not cover the column. The project ids are left
+/// as the `PROJECT_IDS_CONDITION` placeholder.
+pub fn build_trace_aggregates_metric_query(
+    column: NumericColumn,
+    aggregation: Aggregation,
+    group_by_interval: GroupByInterval,
+    time_range: &TimeRange,
+) -> Option<String> {
+    let ch_round_time = group_by_interval.to_ch_truncate_time();
+    let ch_aggregation = aggregation.to_ch_agg_function();
+    let metric = match column {
+        NumericColumn::TotalTokens => "SUM(sum_tokens)",
+        NumericColumn::TotalCost => "SUM(sum_cost)",
+        NumericColumn::TraceLatencySeconds => {
+            "(toUnixTimestamp64Nano(MAX(max_end)) - toUnixTimestamp64Nano(MIN(min_start))) / 1e9"
+        }
+        NumericColumn::PromptTokens
+        | NumericColumn::CompletionTokens
+        | NumericColumn::TraceDepth => return None,
+    };
+    let time_condition = time_range.to_ch_condition("time");
+    let group_by_time_statement = time_range.to_ch_group_by_time(group_by_interval);
+
+    Some(format!(
+        "
+    WITH traces AS (
+    SELECT
+        trace_id,
+        project_id,
+        {ch_round_time}(MIN(min_start)) as time,
+        toFloat64({metric}) as value
+    FROM {TRACE_AGGREGATES_TABLE}
+    WHERE {PROJECT_IDS_CONDITION}
+    GROUP BY project_id, trace_id
+    )
+    SELECT
+        time,
+        ifNull({ch_aggregation}(value), 0) as value
+    FROM traces
+    WHERE {time_condition}
+    {group_by_time_statement}"
+    ))
+}
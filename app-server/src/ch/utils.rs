@@ -1,19 +1,97 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::Mutex,
+    time::Duration,
+};
+
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use clickhouse::Row;
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::db::utils::validate_sql_string;
+use super::{
+    modifiers::{GroupByInterval, TimeRange, DEFAULT_COST_PRECISION},
+    spans::SpanTable,
+    FloatMetricTimeValue, IntMetricTimeValue, MetricTimeValue,
+};
+
+/// Compression of the blocks exchanged with ClickHouse.
+///
+/// LZ4 usually shrinks span data several times over for a little CPU on both ends, which
+/// pays off for large inserts and exports, especially over WAN. On a local network with
+/// CPU-bound servers, `None` may be faster. The client does not support ZSTD.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClientCompression {
+    None,
+    #[default]
+    Lz4,
+}
 
-use super::modifiers::GroupByInterval;
+impl ClientCompression {
+    /// Reads `CLICKHOUSE_COMPRESSION`, `none` or `lz4`, defaulting to LZ4 if it is not set
+    /// or not recognized
+    pub fn from_env() -> Self {
+        match std::env::var("CLICKHOUSE_COMPRESSION") {
+            Ok(value) if value.eq_ignore_ascii_case("none") => ClientCompression::None,
+            Ok(value) if !value.eq_ignore_ascii_case("lz4") => {
+                log::warn!("Unknown CLICKHOUSE_COMPRESSION {}, using lz4", value);
+                ClientCompression::Lz4
+            }
+            _ => ClientCompression::Lz4,
+        }
+    }
+
+    fn to_client_compression(&self) -> clickhouse::Compression {
+        match self {
+            ClientCompression::None => clickhouse::Compression::None,
+            ClientCompression::Lz4 => clickhouse::Compression::Lz4,
+        }
+    }
+}
 
+/// ClickHouse client for `url` with the given compression. Credentials, the database and
+/// settings are added with the client's own builder methods.
+pub fn build_client(url: &str, compression: ClientCompression) -> clickhouse::Client {
+    clickhouse::Client::default()
+        .with_url(url)
+        .with_compression(compression.to_client_compression())
+}
+
+/// Default upper limit on the number of buckets a single metric query may return
+pub const DEFAULT_MAX_BUCKETS: i64 = 1000;
+
+#[derive(Clone, Copy)]
+pub enum BucketLimitStrategy {
+    /// Fail if the requested interval produces too many buckets
+    Error,
+    /// Switch to coarser intervals until the bucket count fits
+    Coarsen,
+}
+
+/// Earliest and latest value of a time column of a project. Both are nanoseconds since
+/// the Unix epoch, so they carry no timezone, and are meant to be read through
+/// `min_datetime` and `max_datetime`.
 #[derive(Deserialize, Row)]
 pub struct TimeBounds {
     pub min_time: i64,
     pub max_time: i64,
 }
 
+impl TimeBounds {
+    pub fn min_datetime(&self) -> DateTime<Utc> {
+        nanoseconds_to_chrono(self.min_time)
+    }
+
+    pub fn max_datetime(&self) -> DateTime<Utc> {
+        nanoseconds_to_chrono(self.max_time)
+    }
+}
+
 pub fn chrono_to_nanoseconds(chrono_dt: DateTime<Utc>) -> i64 {
     let timestamp = chrono_dt.timestamp(); // seconds since the Unix epoch
     let nanos = chrono_dt.timestamp_subsec_nanos(); // nanoseconds part
@@ -43,6 +121,37 @@ pub fn group_by_time_absolute_statement(
     start_time: DateTime<Utc>,
     end_time: DateTime<Utc>,
     group_by_interval: GroupByInterval,
+) -> String {
+    format!(
+        "GROUP BY
+            time
+        ORDER BY
+            time
+        {}",
+        with_fill_absolute_statement(start_time, end_time, group_by_interval)
+    )
+}
+
+pub fn group_by_time_relative_statement(
+    past_hours: i64,
+    group_by_interval: GroupByInterval,
+) -> String {
+    format!(
+        "GROUP BY
+            time
+        ORDER BY
+            time
+        {}",
+        with_fill_relative_statement(past_hours, group_by_interval)
+    )
+}
+
+/// `WITH FILL` modifier for the `time` column of an absolute time range. Any columns
+/// preceding `time` in `ORDER BY` are filled independently.
+pub fn with_fill_absolute_statement(
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    group_by_interval: GroupByInterval,
 ) -> String {
     let ch_round_time = group_by_interval.to_ch_truncate_time();
     let ch_interval = group_by_interval.to_interval();
@@ -51,37 +160,91 @@ pub fn group_by_time_absolute_statement(
     let ch_end_time = end_time.timestamp();
 
     format!(
-        "GROUP BY
-            time
-        ORDER BY
-            time
-        WITH FILL
+        "WITH FILL
         FROM {ch_round_time}(fromUnixTimestamp({ch_start_time}))
         TO {ch_round_time}(fromUnixTimestamp({ch_end_time}) + INTERVAL {ch_interval})
         STEP {ch_step}"
     )
 }
 
-pub fn group_by_time_relative_statement(
-    past_hours: i64,
-    group_by_interval: GroupByInterval,
-) -> String {
+pub fn with_fill_relative_statement(past_hours: i64, group_by_interval: GroupByInterval) -> String {
     let ch_round_time = group_by_interval.to_ch_truncate_time();
     let ch_interval = group_by_interval.to_interval();
     let ch_step = group_by_interval.to_ch_step();
 
     format!(
-        "GROUP BY
-            time
-        ORDER BY
-            time
-        WITH FILL
+        "WITH FILL
         FROM {ch_round_time}(NOW() - INTERVAL {past_hours} HOUR + INTERVAL {ch_interval})
         TO {ch_round_time}(NOW() + INTERVAL {ch_interval})
         STEP {ch_step}"
     )
 }
 
+/// Magnitude below which metric values are treated as 0
+const SMALL_VALUE_EPSILON: f64 = 1e-6;
+
+/// Rounds values that are 0 up to floating point noise, such as latencies computed
+/// from nanosecond differences, to exactly 0
+pub fn round_small_values_to_zero(value: f64) -> f64 {
+    if value.abs() < SMALL_VALUE_EPSILON {
+        0.0
+    } else {
+        value
+    }
+}
+
+/// Divides each bucket value by the bucket width in seconds, so that counts are
+/// comparable across intervals
+pub fn to_per_second_rate(
+    values: Vec<IntMetricTimeValue>,
+    group_by_interval: GroupByInterval,
+) -> Vec<FloatMetricTimeValue> {
+    let bucket_seconds = group_by_interval.to_seconds() as f64;
+    values
+        .into_iter()
+        .map(|v| MetricTimeValue {
+            time: v.time,
+            value: v.value as f64 / bucket_seconds,
+        })
+        .collect()
+}
+
+/// Number of buckets `WITH FILL` produces for the time range, including both ends
+pub fn bucket_count(time_range: &TimeRange, group_by_interval: GroupByInterval) -> i64 {
+    time_range.duration_seconds().max(0) / group_by_interval.to_seconds() + 1
+}
+
+/// Returns the interval that should actually be used to keep the number of buckets
+/// at or below `max_buckets`.
+pub fn limit_bucket_count(
+    time_range: &TimeRange,
+    group_by_interval: GroupByInterval,
+    max_buckets: i64,
+    strategy: BucketLimitStrategy,
+) -> Result<GroupByInterval> {
+    let mut interval = group_by_interval;
+    loop {
+        let count = bucket_count(time_range, interval);
+        if count <= max_buckets {
+            return Ok(interval);
+        }
+        let coarser = match strategy {
+            BucketLimitStrategy::Error => None,
+            BucketLimitStrategy::Coarsen => interval.coarser(),
+        };
+        match coarser {
+            Some(coarser) => interval = coarser,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Time range produces {} buckets, which exceeds the limit of {}",
+                    count,
+                    max_buckets
+                ));
+            }
+        }
+    }
+}
+
 // Template ID is not included here for events, so that all graphs in the event dashboard
 // have the same time bounds. If we want to change that logic, we can optionally add
 // template_id to the WHERE clause.
@@ -91,12 +254,8 @@ async fn get_time_bounds(
     table_name: &str,
     column_name: &str,
 ) -> Result<TimeBounds> {
-    if !validate_sql_string(&table_name) {
-        return Err(anyhow::anyhow!("Invalid table name: {}", table_name));
-    }
-    if !validate_sql_string(&column_name) {
-        return Err(anyhow::anyhow!("Invalid column name: {}", column_name));
-    }
+    validate_identifier(table_name)?;
+    validate_identifier(column_name)?;
     let query_string = format!(
         "SELECT
             MIN({column_name}) AS min_time,
@@ -112,6 +271,58 @@ async fn get_time_bounds(
     Ok(time_bounds)
 }
 
+#[derive(Deserialize, Row)]
+struct ProjectTimeBounds {
+    #[serde(with = "clickhouse::serde::uuid")]
+    project_id: Uuid,
+    min_time: i64,
+    max_time: i64,
+}
+
+/// Time bounds of many projects in one query. Projects without rows have no entry.
+pub async fn get_time_bounds_multi(
+    clickhouse: &clickhouse::Client,
+    project_ids: &[Uuid],
+    table_name: &str,
+    column_name: &str,
+) -> Result<HashMap<Uuid, TimeBounds>> {
+    if project_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    validate_identifier(table_name)?;
+    validate_identifier(column_name)?;
+    let query_string = format!(
+        "SELECT
+            project_id,
+            MIN({column_name}) AS min_time,
+            MAX({column_name}) AS max_time
+        FROM
+            {table_name}
+        WHERE {PROJECT_IDS_CONDITION}
+        GROUP BY project_id",
+    );
+
+    let rows = clickhouse
+        .query(&query_string)
+        .bind(project_ids)
+        .fetch_all::<ProjectTimeBounds>()
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.project_id,
+                TimeBounds {
+                    min_time: row.min_time,
+                    max_time: row.max_time,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Earliest and latest value of `column_name` for the project, in UTC
 pub async fn get_bounds(
     clickhouse: &clickhouse::Client,
     project_id: &Uuid,
@@ -119,27 +330,436 @@ pub async fn get_bounds(
     column_name: &str,
 ) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
     let time_bounds = get_time_bounds(clickhouse, project_id, table_name, column_name).await?;
-    Ok((
-        nanoseconds_to_chrono(time_bounds.min_time),
-        nanoseconds_to_chrono(time_bounds.max_time),
-    ))
+    Ok((time_bounds.min_datetime(), time_bounds.max_datetime()))
+}
+
+/// Comment `refresh_aggregate_table` sets on a table once it holds the full history. A
+/// table fed by a materialized view only holds the rows inserted since the view was
+/// created until then.
+const BACKFILLED_TABLE_COMMENT: &str = "backfilled";
+
+/// Runs the `ddl` creating `table` and the materialized view that populates it, which
+/// should all be `IF NOT EXISTS` so that this can run on every startup. The table only
+/// holds rows inserted from then on until `refresh_aggregate_table` backfills it.
+pub async fn create_aggregate_table(
+    clickhouse: &clickhouse::Client,
+    table: &str,
+    ddl: &[String],
+) -> Result<()> {
+    for statement in ddl {
+        clickhouse
+            .query(statement)
+            .execute()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {:?}", table, e))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Row)]
+struct ServerTime {
+    now: i64,
+}
+
+#[derive(Deserialize, Row)]
+struct SpanCount {
+    count: u64,
+}
+
+/// Rebuilds `table` from the spans of `span_table`. `select` gets the spans to aggregate
+/// as its source and must produce the columns of `table`.
+///
+/// The rows are written to a staging copy that is then swapped in atomically, so readers
+/// see the previous contents until the new ones are complete. The materialized view keeps
+/// writing to the live table meanwhile, and those rows would be dropped with it, so
+/// ingestion must be paused: only spans inserted before the refresh started are read, and
+/// the refresh fails without swapping if any span has been inserted since. Spans inserted
+/// between that check and the swap are still lost.
+pub async fn refresh_aggregate_table(
+    clickhouse: &clickhouse::Client,
+    table: &str,
+    span_table: &SpanTable,
+    select: impl Fn(&str) -> String,
+) -> Result<()> {
+    validate_identifier(table)?;
+    require_column(
+        clickhouse,
+        span_table.as_str(),
+        "inserted_at",
+        "008000-span-inserted-at.sql",
+    )
+    .await?;
+    let started_at = clickhouse
+        .query("SELECT toUnixTimestamp64Nano(now64(9)) AS now")
+        .fetch_one::<ServerTime>()
+        .await?
+        .now;
+    let source = format!(
+        "(SELECT * FROM {span_table} WHERE inserted_at < fromUnixTimestamp64Nano({started_at}))"
+    );
+    let staging_table = format!("{table}_staging");
+
+    let backfill = [
+        format!("DROP TABLE IF EXISTS {staging_table}"),
+        format!("CREATE TABLE {staging_table} AS {table}"),
+        format!("INSERT INTO {staging_table} {}", select(&source)),
+    ];
+    for statement in backfill {
+        clickhouse
+            .query(&statement)
+            .execute()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to refresh {}: {:?}", table, e))?;
+    }
+
+    let inserted = clickhouse
+        .query(&format!(
+            "SELECT count() AS count FROM {span_table}
+            WHERE inserted_at >= fromUnixTimestamp64Nano(?)"
+        ))
+        .bind(started_at)
+        .fetch_one::<SpanCount>()
+        .await?;
+    if inserted.count > 0 {
+        clickhouse
+            .query(&format!("DROP TABLE IF EXISTS {staging_table}"))
+            .execute()
+            .await?;
+        return Err(anyhow::anyhow!(
+            "{} spans were inserted while refreshing {}; pause ingestion and retry",
+            inserted.count,
+            table
+        ));
+    }
+
+    let swap = [
+        format!("EXCHANGE TABLES {staging_table} AND {table}"),
+        format!("DROP TABLE {staging_table}"),
+        format!("ALTER TABLE {table} MODIFY COMMENT '{BACKFILLED_TABLE_COMMENT}'"),
+    ];
+    for statement in swap {
+        clickhouse
+            .query(&statement)
+            .execute()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to refresh {}: {:?}", table, e))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Row)]
+struct TableCount {
+    count: u64,
+}
+
+/// Whether `table` exists and has been backfilled by `refresh_aggregate_table`, i.e. can
+/// be read instead of the spans it aggregates
+pub async fn aggregate_table_backfilled(
+    clickhouse: &clickhouse::Client,
+    options: &QueryOptions,
+    table: &str,
+) -> Result<bool> {
+    let query = options
+        .apply(clickhouse)
+        .query(
+            "SELECT count() AS count FROM system.tables
+            WHERE database = currentDatabase() AND name = ? AND comment = ?",
+        )
+        .bind(table)
+        .bind(BACKFILLED_TABLE_COMMENT);
+    let rows = options
+        .run(clickhouse, async {
+            Ok(query.fetch_all::<TableCount>().await?)
+        })
+        .await?;
+
+    Ok(rows.first().is_some_and(|row| row.count > 0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum QueryError {
+    #[error("Clickhouse query timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Clickhouse setting {0} is not allowed")]
+    SettingNotAllowed(String),
+    #[error("Clickhouse query was cancelled")]
+    Cancelled,
+}
+
+/// ClickHouse settings callers may override per query. Anything else is rejected, so
+/// that user input can't reach arbitrary settings.
+pub const ALLOWED_QUERY_SETTINGS: &[&str] = &[
+    "max_memory_usage",
+    "max_bytes_before_external_group_by",
+    "max_bytes_before_external_sort",
+    "max_threads",
+    "max_rows_to_read",
+    "max_result_rows",
+];
+
+/// Execution options applied to read queries
+#[derive(Clone, Default)]
+pub struct QueryOptions {
+    /// Sets ClickHouse's `max_execution_time` and also bounds the wait on our side,
+    /// failing with `QueryError::Timeout`
+    pub timeout: Option<Duration>,
+    /// Extra ClickHouse settings, limited to `ALLOWED_QUERY_SETTINGS`
+    settings: HashMap<String, String>,
+    /// Return empty strings instead of the `"<null>"` sentinel in fetched spans
+    pub strip_sentinels: bool,
+    /// Token and ClickHouse query id of a cancelable query
+    cancellation: Option<(CancellationToken, String)>,
+    /// Decimal places of cost values, `DEFAULT_COST_PRECISION` if not set
+    cost_precision: Option<u32>,
+}
+
+impl QueryOptions {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Makes the query cancelable. Once `token` is cancelled, `run` stops waiting for the
+    /// query, kills it on the server and fails with `QueryError::Cancelled`.
+    ///
+    /// The query is identified by a query id generated here, so options with a
+    /// cancellation token must not be shared by concurrent queries, see
+    /// `for_concurrent_query`.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some((token, Uuid::new_v4().to_string()));
+        self
+    }
+
+    /// Copy of the options for a query running concurrently with the ones using `self`.
+    /// It gets its own query id, so that the same token cancels all of them.
+    pub fn for_concurrent_query(&self) -> Self {
+        let mut options = self.clone();
+        if let Some((token, _)) = &self.cancellation {
+            options.cancellation = Some((token.clone(), Uuid::new_v4().to_string()));
+        }
+        options
+    }
+
+    pub fn with_strip_sentinels(mut self) -> Self {
+        self.strip_sentinels = true;
+        self
+    }
+
+    /// Rounds cost values in the output of cost metrics and summaries to `decimals`
+    /// decimal places
+    pub fn with_cost_precision(mut self, decimals: u32) -> Self {
+        self.cost_precision = Some(decimals);
+        self
+    }
+
+    pub fn cost_precision(&self) -> u32 {
+        self.cost_precision.unwrap_or(DEFAULT_COST_PRECISION)
+    }
+
+    /// Adds ClickHouse settings such as `max_memory_usage`, failing on settings outside
+    /// of `ALLOWED_QUERY_SETTINGS`
+    pub fn with_settings(mut self, settings: HashMap<String, String>) -> Result<Self> {
+        for name in settings.keys() {
+            validate_identifier(name)?;
+        }
+        if let Some(name) = settings
+            .keys()
+            .find(|name| !ALLOWED_QUERY_SETTINGS.contains(&name.as_str()))
+        {
+            return Err(QueryError::SettingNotAllowed(name.clone()).into());
+        }
+
+        self.settings.extend(settings);
+        Ok(self)
+    }
+
+    /// Client with the ClickHouse settings for these options applied
+    pub fn apply(&self, clickhouse: &clickhouse::Client) -> clickhouse::Client {
+        let mut clickhouse = clickhouse.clone();
+        if let Some(timeout) = self.timeout {
+            // max_execution_time has seconds granularity, round up so that it never
+            // fires before the client-side timeout
+            let seconds = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+            clickhouse = clickhouse.with_option("max_execution_time", seconds.to_string());
+        }
+        for (name, value) in &self.settings {
+            clickhouse = clickhouse.with_option(name, value);
+        }
+        if let Some((_, query_id)) = &self.cancellation {
+            clickhouse = clickhouse.with_option("query_id", query_id);
+        }
+        // Stop the query on the server as soon as a dropped future closes the connection
+        clickhouse.with_option("cancel_http_readonly_queries_on_client_close", "1")
+    }
+
+    /// Runs a query built with a client from `apply`. `clickhouse` must be the original
+    /// client, it is used to kill the query on cancellation.
+    pub async fn run<T, F>(&self, clickhouse: &clickhouse::Client, future: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let future = async {
+            match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, future)
+                    .await
+                    .map_err(|_| QueryError::Timeout(timeout))?,
+                None => future.await,
+            }
+        };
+
+        match &self.cancellation {
+            Some((token, query_id)) => tokio::select! {
+                res = future => res,
+                _ = token.cancelled() => {
+                    kill_query(clickhouse, query_id).await;
+                    Err(QueryError::Cancelled.into())
+                }
+            },
+            None => future.await,
+        }
+    }
+}
+
+async fn kill_query(clickhouse: &clickhouse::Client, query_id: &str) {
+    let res = clickhouse
+        .query("KILL QUERY WHERE query_id = ? ASYNC")
+        .bind(query_id)
+        .execute()
+        .await;
+    if let Err(e) = res {
+        log::warn!("Failed to kill cancelled query {}: {:?}", query_id, e);
+    }
 }
 
 pub async fn execute_query<'de, T>(
     clickhouse: &clickhouse::Client,
+    options: &QueryOptions,
+    query_string: &str,
+) -> Result<Vec<T>>
+where
+    T: Row + Deserialize<'de>,
+{
+    let query_client = options.apply(clickhouse);
+
+    options
+        .run(clickhouse, async {
+            let mut cursor = query_client.query(query_string).fetch::<T>()?;
+
+            let mut res = Vec::new();
+            while let Some(row) = cursor.next().await? {
+                res.push(row);
+            }
+
+            Ok(res)
+        })
+        .await
+}
+
+/// Condition restricting a query to a list of projects, bound as one array parameter
+pub const PROJECT_IDS_CONDITION: &str = "project_id IN ?";
+
+/// Like `execute_query`, for queries whose only placeholder is `PROJECT_IDS_CONDITION`
+pub async fn execute_project_query<'de, T>(
+    clickhouse: &clickhouse::Client,
+    options: &QueryOptions,
     query_string: &str,
+    project_ids: &[Uuid],
 ) -> Result<Vec<T>>
 where
     T: Row + Deserialize<'de>,
 {
-    let mut cursor = clickhouse.query(query_string).fetch::<T>()?;
+    let query_client = options.apply(clickhouse);
+
+    options
+        .run(clickhouse, async {
+            let mut cursor = query_client
+                .query(query_string)
+                .bind(project_ids)
+                .fetch::<T>()?;
+
+            let mut res = Vec::new();
+            while let Some(row) = cursor.next().await? {
+                res.push(row);
+            }
 
-    let mut res = Vec::new();
-    while let Some(row) = cursor.next().await? {
-        res.push(row);
+            Ok(res)
+        })
+        .await
+}
+
+lazy_static! {
+    /// `(table, column)` pairs known to exist. Missing columns are not cached, since a
+    /// migration may add them while the server is running.
+    static ref EXISTING_COLUMNS: Mutex<HashSet<(String, String)>> = Mutex::new(HashSet::new());
+}
+
+#[derive(Deserialize, Row)]
+struct ColumnCount {
+    count: u64,
+}
+
+/// Whether `table_name` has `column`, e.g. one of the optional span columns that older
+/// deployments may not have migrated to yet
+pub async fn has_column(
+    clickhouse: &clickhouse::Client,
+    table_name: &str,
+    column: &str,
+) -> Result<bool> {
+    let key = (table_name.to_string(), column.to_string());
+    if EXISTING_COLUMNS.lock().unwrap().contains(&key) {
+        return Ok(true);
     }
 
-    Ok(res)
+    let row = clickhouse
+        .query(
+            "SELECT count() AS count FROM system.columns
+            WHERE database = currentDatabase() AND table = ? AND name = ?",
+        )
+        .bind(table_name)
+        .bind(column)
+        .fetch_one::<ColumnCount>()
+        .await?;
+    let exists = row.count > 0;
+    if exists {
+        EXISTING_COLUMNS.lock().unwrap().insert(key);
+    }
+
+    Ok(exists)
+}
+
+/// Fails with a pointer to `migration` if `table_name` lacks `column`, instead of the raw
+/// ClickHouse error the query would fail with
+pub async fn require_column(
+    clickhouse: &clickhouse::Client,
+    table_name: &str,
+    column: &str,
+    migration: &str,
+) -> Result<()> {
+    if !has_column(clickhouse, table_name, column).await? {
+        return Err(anyhow::anyhow!(
+            "Column {} is not present in table {}; run migration {}",
+            column,
+            table_name,
+            migration
+        ));
+    }
+    Ok(())
+}
+
+lazy_static! {
+    static ref IDENTIFIER_REGEX: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+}
+
+/// Checks that a table, column, setting or function name is a plain identifier. Such
+/// names can't be bound as parameters, so everything interpolating them into SQL must
+/// validate them here first.
+pub fn validate_identifier(identifier: &str) -> Result<()> {
+    if !IDENTIFIER_REGEX.is_match(identifier) {
+        return Err(anyhow::anyhow!("Invalid identifier: {}", identifier));
+    }
+    Ok(())
 }
 
 /// Trivial SQL injection protection
@@ -153,3 +773,54 @@ pub fn validate_string_against_injection(s: &str) -> Result<()> {
     }
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::{DateTime, Utc};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{QueryOptions, TimeBounds};
+
+    #[test]
+    fn test_time_bounds_datetimes() {
+        let bounds = TimeBounds {
+            min_time: 1_700_000_000_123_456_789,
+            max_time: 1_700_003_600_000_000_000,
+        };
+
+        assert_eq!(
+            bounds.min_datetime(),
+            DateTime::<Utc>::from_timestamp(1_700_000_000, 123_456_789).unwrap()
+        );
+        assert_eq!(
+            bounds.max_datetime().to_rfc3339(),
+            "2023-11-14T23:13:20+00:00"
+        );
+    }
+
+    #[test]
+    fn test_concurrent_queries_get_their_own_query_id() {
+        let options = QueryOptions::default().with_cancellation(CancellationToken::new());
+        let concurrent = options.for_concurrent_query();
+
+        let (_, query_id) = options.cancellation.as_ref().unwrap();
+        let (_, concurrent_query_id) = concurrent.cancellation.as_ref().unwrap();
+        assert_ne!(query_id, concurrent_query_id);
+        assert!(QueryOptions::default()
+            .for_concurrent_query()
+            .cancellation
+            .is_none());
+    }
+
+    #[test]
+    fn test_query_settings_allowlist() {
+        let allowed = HashMap::from([("max_memory_usage".to_string(), "10000000000".to_string())]);
+        assert!(QueryOptions::default().with_settings(allowed).is_ok());
+
+        let injected =
+            HashMap::from([("readonly=0&max_memory_usage".to_string(), "1".to_string())]);
+        assert!(QueryOptions::default().with_settings(injected).is_err());
+    }
+}
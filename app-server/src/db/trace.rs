@@ -11,13 +11,14 @@ use uuid::Uuid;
 use crate::{
     db::modifiers::DateRange,
     language_model::{ChatMessage, ChatMessageContent, InstrumentationChatMessageContentPart},
-    opentelemetry::opentelemetry_proto_trace_v1::Span as OtelSpan,
+    opentelemetry::opentelemetry_proto_trace_v1::{status::StatusCode, Span as OtelSpan},
     pipeline::{nodes::Message, trace::MetaLog},
     traces::{
         attributes::{
-            ASSOCIATION_PROPERTIES_PREFIX, GEN_AI_INPUT_COST, GEN_AI_INPUT_TOKENS,
-            GEN_AI_OUTPUT_COST, GEN_AI_OUTPUT_TOKENS, GEN_AI_REQUEST_MODEL, GEN_AI_RESPONSE_MODEL,
-            GEN_AI_SYSTEM, GEN_AI_TOTAL_COST, SPAN_PATH, SPAN_TYPE,
+            ASSOCIATION_PROPERTIES_PREFIX, GEN_AI_CACHED_TOKENS, GEN_AI_INPUT_COST,
+            GEN_AI_INPUT_TOKENS, GEN_AI_OUTPUT_COST, GEN_AI_OUTPUT_TOKENS, GEN_AI_REQUEST_MODEL,
+            GEN_AI_RESPONSE_MODEL, GEN_AI_SYSTEM, GEN_AI_TOTAL_COST, SPAN_PATH, SPAN_STATUS,
+            SPAN_TYPE,
         },
         SpanUsage,
     },
@@ -78,6 +79,23 @@ impl Into<u8> for SpanType {
     }
 }
 
+/// for reading from clickhouse, the inverse of `Into<u8>`
+impl TryFrom<u8> for SpanType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(SpanType::DEFAULT),
+            1 => Ok(SpanType::LLM),
+            2 => Ok(SpanType::PIPELINE),
+            3 => Ok(SpanType::EXECUTOR),
+            4 => Ok(SpanType::EVALUATOR),
+            5 => Ok(SpanType::EVALUATION),
+            _ => Err(anyhow::anyhow!("Unknown span type: {}", value)),
+        }
+    }
+}
+
 fn default_true() -> bool {
     true
 }
@@ -262,6 +280,14 @@ impl SpanAttributes {
         }
     }
 
+    /// `None` if the provider did not report prompt caching
+    pub fn cached_tokens(&self) -> Option<i64> {
+        match self.attributes.get(GEN_AI_CACHED_TOKENS) {
+            Some(Value::Number(n)) => n.as_i64(),
+            _ => None,
+        }
+    }
+
     pub fn request_model(&self) -> Option<String> {
         match self.attributes.get(GEN_AI_REQUEST_MODEL) {
             Some(Value::String(s)) => Some(s.clone()),
@@ -321,6 +347,21 @@ impl SpanAttributes {
             .and_then(|p| p.as_str().map(|s| s.to_string()))
     }
 
+    /// `ok` or `error` if the instrumentation set the status of the span
+    pub fn status(&self) -> Option<String> {
+        self.attributes
+            .get(SPAN_STATUS)
+            .and_then(|s| s.as_str().map(|s| s.to_string()))
+    }
+
+    /// Nesting depth of the span, derived from its path. Root spans and spans without
+    /// a path have depth 0.
+    pub fn depth(&self) -> u32 {
+        self.path()
+            .map(|path| path.split('.').count().saturating_sub(1) as u32)
+            .unwrap_or(0)
+    }
+
     pub fn set_usage(&mut self, usage: &SpanUsage) {
         self.attributes
             .insert(GEN_AI_INPUT_TOKENS.to_string(), json!(usage.prompt_tokens));
@@ -393,12 +434,26 @@ impl Span {
             Some(span_id_to_uuid(&otel_span.parent_span_id))
         };
 
-        let attributes = otel_span
+        let mut attributes = otel_span
             .attributes
             .into_iter()
             .map(|k| (k.key, convert_any_value_to_json_value(k.value)))
             .collect::<serde_json::Map<String, serde_json::Value>>();
 
+        let status = otel_span
+            .status
+            .as_ref()
+            .and_then(|status| StatusCode::try_from(status.code).ok());
+        match status {
+            Some(StatusCode::Ok) => {
+                attributes.insert(SPAN_STATUS.to_string(), json!("ok"));
+            }
+            Some(StatusCode::Error) => {
+                attributes.insert(SPAN_STATUS.to_string(), json!("error"));
+            }
+            _ => {}
+        }
+
         let mut span = Span {
             version: String::from(DEFAULT_VERSION),
             span_id,
@@ -133,12 +133,12 @@ fn main() -> anyhow::Result<()> {
     let clickhouse_password = env::var("CLICKHOUSE_PASSWORD");
     // https://clickhouse.com/docs/en/cloud/bestpractices/asynchronous-inserts -> Create client which will wait for async inserts
     // For now, we're not waiting for inserts to finish, but later need to add queue and batch on client-side
-    let mut clickhouse = clickhouse::Client::default()
-        .with_url(clickhouse_url)
-        .with_user(clickhouse_user)
-        .with_database("default")
-        .with_option("async_insert", "1")
-        .with_option("wait_for_async_insert", "0");
+    let mut clickhouse =
+        ch::utils::build_client(&clickhouse_url, ch::utils::ClientCompression::from_env())
+            .with_user(clickhouse_user)
+            .with_database("default")
+            .with_option("async_insert", "1")
+            .with_option("wait_for_async_insert", "0");
     if let Ok(clickhouse_password) = clickhouse_password {
         clickhouse = clickhouse.with_password(clickhouse_password);
     } else {
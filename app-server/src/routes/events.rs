@@ -1,19 +1,31 @@
-use actix_web::{delete, get, post, web, HttpResponse};
+use actix_web::{
+    delete, get,
+    http::header::{HeaderName, HeaderValue},
+    post, web, HttpResponse,
+};
 use serde::Deserialize;
 use uuid::Uuid;
 
 use crate::{
-    ch::{self, utils::get_bounds, Aggregation},
+    ch::{
+        self,
+        modifiers::TimeRange,
+        utils::{
+            get_bounds, limit_bucket_count, to_per_second_rate, BucketLimitStrategy,
+            DEFAULT_MAX_BUCKETS,
+        },
+        Aggregation,
+    },
     db::{
         self,
         events::EventWithTemplateName,
-        modifiers::{AbsoluteDateInterval, DateRange, Filter, RelativeDateInterval},
+        modifiers::{DateRange, Filter, RelativeDateInterval},
         DB,
     },
     routes::{PaginatedGetQueryParams, PaginatedResponse, DEFAULT_PAGE_SIZE},
 };
 
-use super::{GetMetricsQueryParams, ResponseResult};
+use super::{GetMetricsQueryParams, ResponseResult, GROUP_BY_INTERVAL_HEADER};
 
 #[get("event-templates")]
 pub async fn get_event_templates(path: web::Path<Uuid>, db: web::Data<DB>) -> ResponseResult {
@@ -152,6 +164,10 @@ pub async fn get_events_metrics(
     let aggregation = req.base_params.aggregation;
     let date_range = req.base_params.date_range.as_ref();
     let group_by_interval = req.base_params.group_by_interval;
+    let rate = req.base_params.rate;
+    if req.base_params.cumulative {
+        return Err(anyhow::anyhow!("Cumulative is not supported for event metrics").into());
+    }
 
     let defaulted_range =
         date_range
@@ -160,74 +176,71 @@ pub async fn get_events_metrics(
                 past_hours: "all".to_string(),
             }));
 
-    let range = if let DateRange::Relative(RelativeDateInterval { past_hours }) = &defaulted_range {
-        if past_hours == "all" {
-            let (start_date, end_date) =
-                get_bounds(&clickhouse, &project_id, "events", "timestamp").await?;
-            DateRange::Absolute(AbsoluteDateInterval {
-                start_date,
-                end_date,
-            })
-        } else {
-            defaulted_range
+    let time_range = match defaulted_range {
+        DateRange::Relative(interval) => {
+            if interval.past_hours == "all" {
+                let (start_time, end_time) =
+                    get_bounds(&clickhouse, &project_id, "events", "timestamp").await?;
+                TimeRange::Absolute {
+                    start: start_time,
+                    end: end_time,
+                }
+            } else {
+                let past_hours = interval
+                    .past_hours
+                    .parse::<i64>()
+                    .map_err(|e| anyhow::anyhow!("Failed to parse past_hours as i64: {}", e))?;
+                TimeRange::Relative { past_hours }
+            }
         }
+        DateRange::Absolute(interval) => TimeRange::Absolute {
+            start: interval.start_date,
+            end: interval.end_date,
+        },
+    };
+    let group_by_interval = limit_bucket_count(
+        &time_range,
+        group_by_interval,
+        DEFAULT_MAX_BUCKETS,
+        BucketLimitStrategy::Coarsen,
+    )?;
+    let time_range = if req.base_params.align_to_calendar {
+        time_range.aligned_to_calendar(group_by_interval)
     } else {
-        defaulted_range
+        time_range
     };
 
-    match range {
-        DateRange::Relative(interval) => {
-            let past_hours = interval
-                .past_hours
-                .parse::<i64>()
-                .map_err(|e| anyhow::anyhow!("Failed to parse past_hours as i64: {}", e))?;
-            match metric {
-                EventMetric::EventCount => match aggregation {
-                    Aggregation::Total => {
-                        let values = ch::events::get_total_event_count_metrics_relative(
-                            clickhouse,
-                            group_by_interval,
-                            project_id,
-                            event_template_id,
-                            past_hours,
-                        )
-                        .await?;
-                        Ok(HttpResponse::Ok().json(values))
-                    }
-                    _ => {
-                        return Err(anyhow::anyhow!(
-                            "Unsupported aggregation {:?} for metric {}",
-                            aggregation,
-                            "eventCount"
-                        )
-                        .into());
-                    }
-                },
-            }
-        }
-        DateRange::Absolute(interval) => match metric {
-            EventMetric::EventCount => match aggregation {
-                Aggregation::Total => {
-                    let values = ch::events::get_total_event_count_metrics_absolute(
-                        clickhouse,
-                        group_by_interval,
-                        project_id,
-                        event_template_id,
-                        interval.start_date,
-                        interval.end_date,
-                    )
-                    .await?;
+    let mut response = match metric {
+        EventMetric::EventCount => match aggregation {
+            Aggregation::Total => {
+                let values = ch::events::get_total_event_count_metrics(
+                    clickhouse,
+                    group_by_interval,
+                    project_id,
+                    event_template_id,
+                    time_range,
+                )
+                .await?;
+                if rate {
+                    Ok(HttpResponse::Ok().json(to_per_second_rate(values, group_by_interval)))
+                } else {
                     Ok(HttpResponse::Ok().json(values))
                 }
-                _ => {
-                    return Err(anyhow::anyhow!(
-                        "Unsupported aggregation {:?} for metric {}",
-                        aggregation,
-                        metric
-                    )
-                    .into());
-                }
-            },
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported aggregation {:?} for metric {}",
+                    aggregation,
+                    metric
+                )
+                .into());
+            }
         },
-    }
+    }?;
+    response.headers_mut().insert(
+        HeaderName::from_static(GROUP_BY_INTERVAL_HEADER),
+        HeaderValue::from_static(group_by_interval.as_str()),
+    );
+
+    Ok(response)
 }
@@ -22,6 +22,10 @@ use crate::{
 
 pub const DEFAULT_PAGE_SIZE: usize = 50;
 
+/// Response header of the metric routes with the interval the buckets are grouped by. It
+/// is coarser than the requested one when that would return too many buckets.
+pub const GROUP_BY_INTERVAL_HEADER: &str = "x-group-by-interval";
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PaginatedGetQueryParams {
@@ -63,4 +67,13 @@ struct GetMetricsQueryParams {
     /// Time interval for grouping
     #[serde(default)]
     pub group_by_interval: GroupByInterval,
+    /// Normalize count metrics to values per second
+    #[serde(default)]
+    pub rate: bool,
+    /// Start relative ranges at a bucket boundary so that the first bucket is complete
+    #[serde(default)]
+    pub align_to_calendar: bool,
+    /// Return running totals since the start of the range instead of per-bucket values
+    #[serde(default)]
+    pub cumulative: bool,
 }
@@ -1,8 +1,18 @@
 use super::{GetMetricsQueryParams, ResponseResult};
-use super::{PaginatedGetQueryParams, PaginatedResponse, DEFAULT_PAGE_SIZE};
-use crate::ch::utils::get_bounds;
+use super::{
+    PaginatedGetQueryParams, PaginatedResponse, DEFAULT_PAGE_SIZE, GROUP_BY_INTERVAL_HEADER,
+};
+use crate::ch::utils::{
+    get_bounds, limit_bucket_count, to_per_second_rate, BucketLimitStrategy, QueryOptions,
+    DEFAULT_MAX_BUCKETS,
+};
 use crate::{
-    ch::{self, modifiers::GroupByInterval, Aggregation},
+    ch::{
+        self,
+        modifiers::{GroupByInterval, NegativeLatencyPolicy, TimeRange},
+        spans::SpanTable,
+        Aggregation, CountMode,
+    },
     db::{
         self,
         events::EventWithTemplateName,
@@ -11,8 +21,11 @@ use crate::{
         DB,
     },
 };
-use actix_web::{get, post, web, HttpResponse};
-use chrono::{DateTime, Utc};
+use actix_web::{
+    get,
+    http::header::{HeaderName, HeaderValue},
+    post, web, HttpResponse,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use uuid::Uuid;
@@ -148,6 +161,8 @@ pub async fn get_traces_metrics(
     let aggregation = req.base_params.aggregation;
     let date_range = req.base_params.date_range.as_ref();
     let group_by_interval = req.base_params.group_by_interval;
+    let rate = req.base_params.rate;
+    let cumulative = req.base_params.cumulative;
 
     // We expect the frontend to always provide a date range.
     // However, for smooth UX we default this to all time.
@@ -158,50 +173,57 @@ pub async fn get_traces_metrics(
                 past_hours: "all".to_string(),
             }));
 
-    match defaulted_range {
+    let time_range = match defaulted_range {
         DateRange::Relative(interval) => {
             if interval.past_hours == "all" {
                 let (start_time, end_time) =
                     get_bounds(&clickhouse, &project_id, "spans", "start_time").await?;
-                return get_metrics_absolute_time(
-                    clickhouse.clone(),
-                    metric,
-                    project_id,
-                    start_time,
-                    end_time,
-                    group_by_interval,
-                    aggregation,
-                )
-                .await;
+                TimeRange::Absolute {
+                    start: start_time,
+                    end: end_time,
+                }
             } else {
                 let past_hours = interval
                     .past_hours
                     .parse::<i64>()
                     .map_err(|e| anyhow::anyhow!("Failed to parse past_hours as i64: {}", e))?;
-                get_metrics_relative_time(
-                    clickhouse.clone(),
-                    metric,
-                    project_id,
-                    past_hours,
-                    group_by_interval,
-                    aggregation,
-                )
-                .await
+                TimeRange::Relative { past_hours }
             }
         }
-        DateRange::Absolute(interval) => {
-            get_metrics_absolute_time(
-                clickhouse.clone(),
-                metric,
-                project_id,
-                interval.start_date,
-                interval.end_date,
-                group_by_interval,
-                aggregation,
-            )
-            .await
-        }
-    }
+        DateRange::Absolute(interval) => TimeRange::Absolute {
+            start: interval.start_date,
+            end: interval.end_date,
+        },
+    };
+    let group_by_interval = limit_bucket_count(
+        &time_range,
+        group_by_interval,
+        DEFAULT_MAX_BUCKETS,
+        BucketLimitStrategy::Coarsen,
+    )?;
+    let time_range = if req.base_params.align_to_calendar {
+        time_range.aligned_to_calendar(group_by_interval)
+    } else {
+        time_range
+    };
+
+    let mut response = get_metrics(
+        clickhouse,
+        metric,
+        project_id,
+        time_range,
+        group_by_interval,
+        aggregation,
+        rate,
+        cumulative,
+    )
+    .await?;
+    response.headers_mut().insert(
+        HeaderName::from_static(GROUP_BY_INTERVAL_HEADER),
+        HeaderValue::from_static(group_by_interval.as_str()),
+    );
+
+    Ok(response)
 }
 
 #[get("sessions")]
@@ -232,142 +254,70 @@ pub async fn get_sessions(
     Ok(HttpResponse::Ok().json(response))
 }
 
-async fn get_metrics_relative_time(
+async fn get_metrics(
     clickhouse: clickhouse::Client,
     metric: TraceMetric,
     project_id: Uuid,
-    past_hours: i64,
+    time_range: TimeRange,
     group_by_interval: GroupByInterval,
     aggregation: Aggregation,
+    rate: bool,
+    cumulative: bool,
 ) -> ResponseResult {
-    match metric {
-        TraceMetric::TraceCount => match aggregation {
-            Aggregation::Average => {
-                return Err(anyhow::anyhow!(
-                    "Average grouping is not supported for traceCount metric"
-                )
-                .into());
-            }
-            Aggregation::Total => {
-                let values = ch::spans::get_total_trace_count_metrics_relative(
-                    clickhouse,
-                    group_by_interval,
-                    project_id,
-                    past_hours,
-                )
-                .await?;
-
-                Ok(HttpResponse::Ok().json(values))
-            }
-        },
-        TraceMetric::TraceLatencySeconds => match aggregation {
-            Aggregation::Total => {
-                return Err(anyhow::anyhow!(
-                    "Total grouping is not supported for traceLatency metric"
-                )
-                .into());
-            }
-            Aggregation::Average => {
-                let values = ch::spans::get_trace_latency_seconds_metrics_relative(
-                    clickhouse,
-                    group_by_interval,
-                    project_id,
-                    past_hours,
-                    aggregation,
-                )
-                .await?;
-
-                Ok(HttpResponse::Ok().json(values))
-            }
-        },
-        TraceMetric::TotalTokenCount => match aggregation {
-            Aggregation::Total => {
-                let values = ch::spans::get_total_token_count_metrics_relative(
-                    clickhouse,
-                    group_by_interval,
-                    project_id,
-                    past_hours,
-                    aggregation,
-                )
-                .await?;
-
-                Ok(HttpResponse::Ok().json(values))
-            }
-            Aggregation::Average => {
-                return Err(anyhow::anyhow!(
-                    "Average grouping is not supported for totalTokenCount metric"
-                )
-                .into());
-            }
-        },
-        TraceMetric::CostUsd => match aggregation {
-            Aggregation::Total => {
-                let values = ch::spans::get_cost_usd_metrics_relative(
-                    clickhouse,
-                    group_by_interval,
-                    project_id,
-                    past_hours,
-                    aggregation,
-                )
-                .await?;
-
-                Ok(HttpResponse::Ok().json(values))
-            }
-            Aggregation::Average => {
-                return Err(anyhow::anyhow!(
-                    "Average grouping is not supported for costUsd metric"
-                )
-                .into());
-            }
-        },
+    if rate && !matches!(metric, TraceMetric::TraceCount) {
+        return Err(anyhow::anyhow!("Rate is only supported for the traceCount metric").into());
+    }
+    if cumulative && !matches!(metric, TraceMetric::CostUsd) {
+        return Err(anyhow::anyhow!("Cumulative is only supported for the costUsd metric").into());
     }
-}
 
-async fn get_metrics_absolute_time(
-    clickhouse: clickhouse::Client,
-    metric: TraceMetric,
-    project_id: Uuid,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-    group_by_interval: GroupByInterval,
-    aggregation: Aggregation,
-) -> ResponseResult {
     match metric {
         TraceMetric::TraceCount => match aggregation {
-            Aggregation::Average => {
+            Aggregation::Average | Aggregation::Median => {
                 return Err(anyhow::anyhow!(
-                    "Average grouping is not supported for traceCount metric"
+                    "{:?} grouping is not supported for traceCount metric",
+                    aggregation
                 )
                 .into());
             }
             Aggregation::Total => {
-                let values = ch::spans::get_total_trace_count_metrics_absolute(
+                let values = ch::spans::get_total_trace_count_metrics(
                     clickhouse,
+                    &SpanTable::default(),
+                    &QueryOptions::default(),
                     group_by_interval,
                     project_id,
-                    start_time,
-                    end_time,
+                    time_range,
+                    1,
+                    CountMode::Exact,
                 )
                 .await?;
 
-                Ok(HttpResponse::Ok().json(values))
+                if rate {
+                    Ok(HttpResponse::Ok().json(to_per_second_rate(values, group_by_interval)))
+                } else {
+                    Ok(HttpResponse::Ok().json(values))
+                }
             }
         },
         TraceMetric::TraceLatencySeconds => match aggregation {
             Aggregation::Total => {
                 return Err(anyhow::anyhow!(
-                    "Total grouping is not supported for traceLatency metric"
+                    "{:?} grouping is not supported for traceLatency metric",
+                    aggregation
                 )
                 .into());
             }
-            Aggregation::Average => {
-                let values = ch::spans::get_trace_latency_seconds_metrics_absolute(
+            Aggregation::Average | Aggregation::Median => {
+                let values = ch::spans::get_trace_latency_seconds_metrics(
                     clickhouse,
+                    &SpanTable::default(),
+                    &QueryOptions::default(),
                     group_by_interval,
                     project_id,
-                    start_time,
-                    end_time,
+                    time_range,
                     aggregation,
+                    NegativeLatencyPolicy::default(),
                 )
                 .await?;
 
@@ -376,42 +326,48 @@ async fn get_metrics_absolute_time(
         },
         TraceMetric::TotalTokenCount => match aggregation {
             Aggregation::Total => {
-                let values = ch::spans::get_total_token_count_metrics_absolute(
+                let values = ch::spans::get_total_token_count_metrics(
                     clickhouse,
+                    &SpanTable::default(),
+                    &QueryOptions::default(),
                     group_by_interval,
                     project_id,
-                    start_time,
-                    end_time,
+                    time_range,
                     aggregation,
                 )
                 .await?;
 
                 Ok(HttpResponse::Ok().json(values))
             }
-            Aggregation::Average => {
+            Aggregation::Average | Aggregation::Median => {
                 return Err(anyhow::anyhow!(
-                    "Average grouping is not supported for totalTokenCount metric"
+                    "{:?} grouping is not supported for totalTokenCount metric",
+                    aggregation
                 )
                 .into());
             }
         },
         TraceMetric::CostUsd => match aggregation {
             Aggregation::Total => {
-                let values = ch::spans::get_cost_usd_metrics_absolute(
+                let values = ch::spans::get_cost_usd_metrics(
                     clickhouse,
+                    &SpanTable::default(),
+                    &QueryOptions::default(),
                     group_by_interval,
                     project_id,
-                    start_time,
-                    end_time,
+                    time_range,
                     aggregation,
+                    None,
+                    cumulative,
                 )
                 .await?;
 
                 Ok(HttpResponse::Ok().json(values))
             }
-            Aggregation::Average => {
+            Aggregation::Average | Aggregation::Median => {
                 return Err(anyhow::anyhow!(
-                    "Average grouping is not supported for costUsd metric"
+                    "{:?} grouping is not supported for costUsd metric",
+                    aggregation
                 )
                 .into());
             }
@@ -7,6 +7,8 @@
 // library. We should update the library to send the correct attributes.
 pub const GEN_AI_INPUT_TOKENS: &str = "gen_ai.usage.prompt_tokens";
 pub const GEN_AI_OUTPUT_TOKENS: &str = "gen_ai.usage.completion_tokens";
+// Prompt tokens served from the provider's prompt cache, a subset of the prompt tokens
+pub const GEN_AI_CACHED_TOKENS: &str = "gen_ai.usage.cached_tokens";
 
 // pub const GEN_AI_TOTAL_TOKENS: &str = "gen_ai.usage.total_tokens";
 pub const GEN_AI_REQUEST_MODEL: &str = "gen_ai.request.model";
@@ -26,6 +28,7 @@ pub const GEN_AI_OUTPUT_COST: &str = "gen_ai.usage.output_cost";
 pub const ASSOCIATION_PROPERTIES_PREFIX: &str = "lmnr.association.properties.";
 pub const SPAN_TYPE: &str = "lmnr.span.type";
 pub const SPAN_PATH: &str = "lmnr.span.path";
+pub const SPAN_STATUS: &str = "lmnr.span.status";
 pub const EVENT_TYPE: &str = "lmnr.event.type";
 pub const EVENT_DATA: &str = "lmnr.event.data";
 pub const EVENT_ENV: &str = "lmnr.event.env";
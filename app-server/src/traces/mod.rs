@@ -7,7 +7,10 @@ use lapin::{options::BasicConsumeOptions, options::*, types::FieldTable, Connect
 use crate::{
     api::v1::traces::RabbitMqSpanMessage,
     cache::Cache,
-    ch::{self, spans::CHSpan},
+    ch::{
+        self,
+        spans::{AttributeExtractionConfig, SpanTable},
+    },
     db::{
         events::EventSource,
         trace::{self, Span, SpanAttributes, SpanType, TraceAttributes},
@@ -34,6 +37,9 @@ pub async fn observation_collector(
     rabbitmq_connection: Arc<Connection>,
     clickhouse: clickhouse::Client,
 ) {
+    let attribute_extraction = AttributeExtractionConfig::from_env();
+    let span_table = SpanTable::insert_table_from_env()
+        .expect("CLICKHOUSE_SPANS_INSERT_TABLE must be a valid table name");
     let channel = rabbitmq_connection.create_channel().await.unwrap();
 
     channel
@@ -112,9 +118,16 @@ pub async fn observation_collector(
             log::error!("Failed to record spans: {:?}", e);
         }
 
-        let ch_span = CHSpan::from_db_span(&span, span_usage, rabbitmq_span_message.project_id);
         // TODO: Queue batches on client-side and send them every 1-2 seconds
-        let insert_span_res = ch::spans::insert_span(clickhouse.clone(), &ch_span).await;
+        let insert_span_res = ch::spans::insert_db_span(
+            clickhouse.clone(),
+            &span_table,
+            &span,
+            span_usage,
+            rabbitmq_span_message.project_id,
+            &attribute_extraction,
+        )
+        .await;
         if let Err(e) = insert_span_res {
             log::error!("Failed to insert span into Clickhouse: {:?}", e);
         }
@@ -165,6 +178,9 @@ pub struct SpanUsage {
     pub request_model: Option<String>,
     pub response_model: Option<String>,
     pub provider_name: Option<String>,
+    /// False if the span has a model but no pricing was found for it, in which case the
+    /// costs are 0 without the call being free
+    pub cost_known: bool,
 }
 
 /// Calculate usage for both default and LLM spans
@@ -188,15 +204,18 @@ pub fn get_llm_usage_for_span(
         .and_then(|v| LanguageModelProviderName::from_str(&v.to_lowercase()).ok())
         .and_then(|name| language_model_runner.models.get(&name).cloned());
 
+    // Spans without a model have nothing to price
+    let mut cost_known = model_name.is_none();
+
     // TODO: Think about it. Maybe first see if prices are present in the attributes.
     if let Some(model) = model_name.as_deref() {
         if let Some(provider) = provider {
-            input_cost = provider
-                .estimate_input_cost(model, prompt_tokens as u32)
-                .unwrap_or(0.0);
-            output_cost = provider
-                .estimate_output_cost(model, completion_tokens as u32)
-                .unwrap_or(0.0);
+            let estimated_input_cost = provider.estimate_input_cost(model, prompt_tokens as u32);
+            let estimated_output_cost =
+                provider.estimate_output_cost(model, completion_tokens as u32);
+            cost_known = estimated_input_cost.is_some() && estimated_output_cost.is_some();
+            input_cost = estimated_input_cost.unwrap_or(0.0);
+            output_cost = estimated_output_cost.unwrap_or(0.0);
             total_cost = input_cost + output_cost;
         }
     }
@@ -211,5 +230,6 @@ pub fn get_llm_usage_for_span(
         response_model: attributes.request_model().clone(),
         request_model: attributes.request_model().clone(),
         provider_name,
+        cost_known,
     }
 }
@@ -0,0 +1 @@
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS depth UInt32 DEFAULT 0;
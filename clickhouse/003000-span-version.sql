@@ -0,0 +1 @@
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS updated_at DateTime64(9, 'UTC') DEFAULT toDateTime64(0, 9, 'UTC');
@@ -0,0 +1 @@
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS parent_span_id UUID DEFAULT '00000000-0000-0000-0000-000000000000';
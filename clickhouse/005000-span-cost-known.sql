@@ -0,0 +1 @@
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS cost_known Bool DEFAULT true;
@@ -0,0 +1 @@
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS status String DEFAULT '<null>';
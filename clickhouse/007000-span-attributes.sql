@@ -0,0 +1 @@
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS attributes Map(String, String);
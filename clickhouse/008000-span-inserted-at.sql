@@ -0,0 +1,5 @@
+-- Existing spans get the epoch, so that they are told apart from newly inserted ones
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS inserted_at DateTime64(9, 'UTC') DEFAULT toDateTime64(0, 9, 'UTC');
+ALTER TABLE spans MATERIALIZE COLUMN inserted_at SETTINGS mutations_sync = 2;
+-- Set by the server on insert, as CHSpan does not have the column
+ALTER TABLE spans MODIFY COLUMN inserted_at DateTime64(9, 'UTC') DEFAULT now64(9);
@@ -0,0 +1 @@
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS ingest_source String DEFAULT '<null>';
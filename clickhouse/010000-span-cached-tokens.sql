@@ -0,0 +1,2 @@
+-- NULL when the provider did not report prompt caching
+ALTER TABLE spans ADD COLUMN IF NOT EXISTS cached_tokens Nullable(Int64);
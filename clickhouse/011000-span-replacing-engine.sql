@@ -0,0 +1,13 @@
+-- Keep the latest write of each span by updated_at, which reads with FINAL rely on.
+-- The engine can't be altered in place, so the table is rebuilt and swapped in.
+-- Ingestion must be stopped while this runs: spans inserted after the copy are dropped
+-- with the old table. The rebuild table is created without IF NOT EXISTS, so that a
+-- re-run after a failed attempt stops here instead of copying every span twice; drop
+-- spans_replacing before retrying.
+CREATE TABLE spans_replacing AS spans
+ENGINE = ReplacingMergeTree(updated_at)
+ORDER BY (project_id, start_time, trace_id, span_id)
+SETTINGS index_granularity = 8192;
+INSERT INTO spans_replacing SELECT * FROM spans;
+EXCHANGE TABLES spans_replacing AND spans;
+DROP TABLE spans_replacing;